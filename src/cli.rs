@@ -0,0 +1,283 @@
+/*---------------------------------------------------------------*/
+/*---------------------------[ CLI ]-----------------------------*/
+/*---------------------------------------------------------------*/
+
+use std::{collections::HashMap, io, path::Path};
+
+pub const DEFAULT_CONFIG_FILE: &str = "default.conf";
+
+pub const USAGE: &str = "\
+Usage: webserv [options] [config]
+       webserv explain [options] <method> <url>
+       webserv fmt [--flatten] [options] [config]
+       webserv schema
+
+Options:
+  -c <file>        configuration file (default: default.conf)
+  -t               test the configuration and exit
+  -T               test the configuration, print the effective configuration and exit
+  -s <signal>      send a signal to the running instance: reload, stop, reopen, status
+  -p <prefix>      working directory: relative paths (configuration, roots, pid file) start from it
+  -D <name=value>  define a configuration variable, see ${name} in the configuration
+  -V               print the version and exit
+  -h, --help       print this help and exit
+
+Commands:
+  explain <method> <url>  print the server, location and handler a request would get,
+                          e.g. explain -c my.conf GET http://localhost:8080/index.html
+  fmt                     rewrite the configuration file in place, formatted; its includes
+                          are kept, or replaced by the included files with --flatten
+  schema                  print the JSON schema of TOML, JSON and YAML configurations
+
+Exit status:
+  0  success
+  1  invalid configuration
+  2  invalid command line
+  3  signal not delivered (no running instance, unreadable pid file)
+  4  startup failure (port binding, pid file) or fmt unable to write the file";
+
+/// Exit codes, see `USAGE`
+pub const EXIT_OK: u8 = 0;
+pub const EXIT_CONFIG: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+pub const EXIT_SIGNAL: u8 = 3;
+pub const EXIT_STARTUP: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Run,
+    Test,
+    /// test and print the effective configuration
+    Dump,
+    /// resolve a request offline, see `explain`
+    Explain { method: String, url: String },
+    /// rewrite the configuration file formatted, see `parsing::format_config`
+    Format { flatten: bool },
+    /// print the JSON schema of the structured configurations
+    Schema,
+    Signal(Signal),
+    Version,
+    Help,
+}
+
+/// Signals `-s` sends to the instance whose pid is in the pid file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Reload, //- SIGHUP: reload the configuration
+    Stop, //--- SIGTERM: stop listening and exit
+    Reopen, //- SIGUSR1: reopen the log files
+    Status, //- SIGUSR2: print the connection counters
+}
+
+impl TryFrom<&str> for Signal {
+    type Error = String;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "reload" => Ok(Signal::Reload),
+            "stop" => Ok(Signal::Stop),
+            "reopen" => Ok(Signal::Reopen),
+            "status" => Ok(Signal::Status),
+            _ => Err(format!("-s: unknown signal \"{value}\", expected reload, stop, reopen or status")),
+        }
+    }
+}
+
+impl Signal {
+    fn number(&self) -> libc::c_int {
+        match self {
+            Signal::Reload => libc::SIGHUP,
+            Signal::Stop => libc::SIGTERM,
+            Signal::Reopen => libc::SIGUSR1,
+            Signal::Status => libc::SIGUSR2,
+        }
+    }
+
+    /// Sends the signal to the pid written in `pid_file`
+    pub fn send(&self, pid_file: &Path) -> Result<(), String> {
+        let content = match std::fs::read_to_string(pid_file) {
+            Ok(content) => content,
+            Err(err) => return Err(format!("{}: {err}", pid_file.display())),
+        };
+        let pid = match content.trim().parse::<libc::pid_t>() {
+            Ok(pid) if pid > 0 => pid,
+            _ => return Err(format!("{}: invalid pid \"{}\"", pid_file.display(), content.trim())),
+        };
+
+        // SAFETY: kill only reads its two integer arguments
+        match unsafe { libc::kill(pid, self.number()) } {
+            0 => Ok(()),
+            _ => Err(format!("kill {pid}: {}", io::Error::last_os_error())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub action: Action,
+    pub config: String,
+    pub prefix: Option<String>,
+    /// `-D name=value`
+    pub defines: HashMap<String, String>,
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.into_iter();
+        let mut action = None;
+        let mut config = None;
+        let mut prefix = None;
+        let mut defines = HashMap::new();
+        // operands of `explain`, once it has been given
+        let mut explain: Option<Vec<String>> = None;
+        let mut flatten = false;
+
+        while let Some(arg) = args.next() {
+            let new_action = match arg.as_str() {
+                "-t" | "t" => Some(Action::Test),
+                "-T" => Some(Action::Dump),
+                "-V" | "--version" => Some(Action::Version),
+                "-h" | "--help" => Some(Action::Help),
+                "--flatten" => {
+                    flatten = true;
+                    None
+                }
+                "-s" => {
+                    let signal = Self::value(&mut args, "-s")?;
+                    Some(Action::Signal(Signal::try_from(signal.as_str())?))
+                }
+                "-c" => {
+                    Self::set_once(&mut config, Self::value(&mut args, "-c")?, "config file")?;
+                    None
+                }
+                "-p" => {
+                    Self::set_once(&mut prefix, Self::value(&mut args, "-p")?, "-p")?;
+                    None
+                }
+                _ if arg.starts_with("-D") => {
+                    // "-D name=value" or "-Dname=value"
+                    let define = match &arg[2..] {
+                        "" => Self::value(&mut args, "-D")?,
+                        define => define.to_owned(),
+                    };
+                    match define.split_once('=') {
+                        Some((name, value)) if !name.is_empty() => {
+                            defines.insert(name.to_owned(), value.to_owned())
+                        }
+                        _ => return Err(format!("-D: expected name=value, found \"{define}\"")),
+                    };
+                    None
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option \"{arg}\"")),
+                _ if explain.is_some() => {
+                    explain.get_or_insert_with(Vec::new).push(arg.clone());
+                    None
+                }
+                "fmt" => Some(Action::Format { flatten: false }),
+                "schema" => Some(Action::Schema),
+                "explain" => {
+                    explain = Some(Vec::new());
+                    Some(Action::Explain { method: String::new(), url: String::new() })
+                }
+                // a bare path stays accepted as the configuration file, unless it names a command
+                _ => {
+                    Self::set_once(&mut config, arg.clone(), "config file")?;
+                    None
+                }
+            };
+
+            if let Some(new_action) = new_action {
+                if action.as_ref().is_some_and(|action| *action != new_action) {
+                    return Err(format!("\"{arg}\" cannot be combined with another action"));
+                }
+                action = Some(new_action);
+            }
+        }
+
+        if let Some(mut operands) = explain {
+            if operands.len() != 2 {
+                return Err("explain: expected a method and a URL (explain GET http://localhost:8080/)".to_owned());
+            }
+            let url = operands.remove(1);
+            action = Some(Action::Explain { method: operands.remove(0), url });
+        }
+
+        if flatten {
+            match action {
+                Some(Action::Format { .. }) => action = Some(Action::Format { flatten }),
+                _ => return Err("--flatten is only valid with fmt".to_owned()),
+            }
+        }
+
+        Ok(Args {
+            action: action.unwrap_or(Action::Run),
+            config: config.unwrap_or(DEFAULT_CONFIG_FILE.to_owned()),
+            prefix,
+            defines,
+        })
+    }
+
+    fn value(args: &mut impl Iterator<Item = String>, option: &str) -> Result<String, String> {
+        match args.next() {
+            Some(value) => Ok(value),
+            None => Err(format!("{option}: missing value")),
+        }
+    }
+
+    fn set_once(slot: &mut Option<String>, value: String, name: &str) -> Result<(), String> {
+        match slot {
+            Some(_) => Err(format!("{name} given twice")),
+            None => {
+                *slot = Some(value);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Args, String> {
+        Args::parse(args.split_whitespace().map(str::to_owned))
+    }
+
+    #[test]
+    fn commands_are_recognised_before_and_after_options() {
+        for args in ["-c e.conf explain GET /", "explain -c e.conf GET /", "explain GET / -c e.conf"] {
+            let args = parse(args).unwrap();
+
+            assert_eq!(args.action, Action::Explain { method: "GET".to_owned(), url: "/".to_owned() });
+            assert_eq!(args.config, "e.conf");
+        }
+    }
+
+    #[test]
+    fn fmt_takes_its_file_either_way() {
+        for args in ["-c f.conf fmt --flatten", "fmt --flatten f.conf", "fmt -c f.conf --flatten"] {
+            let args = parse(args).unwrap();
+
+            assert_eq!(args.action, Action::Format { flatten: true });
+            assert_eq!(args.config, "f.conf");
+        }
+    }
+
+    #[test]
+    fn schema_is_a_command_after_options_too() {
+        assert_eq!(parse("schema").unwrap().action, Action::Schema);
+        assert_eq!(parse("-c a.conf schema").unwrap().action, Action::Schema);
+    }
+
+    #[test]
+    fn signals() {
+        assert_eq!(parse("-s status").unwrap().action, Action::Signal(Signal::Status));
+        assert_eq!(parse("-s reopen").unwrap().action, Action::Signal(Signal::Reopen));
+        assert!(parse("-s restart").unwrap_err().contains("expected reload, stop, reopen or status"));
+    }
+
+    #[test]
+    fn bare_path_is_the_configuration_file() {
+        assert_eq!(parse("my.conf").unwrap().config, "my.conf");
+        assert_eq!(parse("-c a.conf b.conf").unwrap_err(), "config file given twice");
+    }
+}
@@ -0,0 +1,523 @@
+/*---------------------------------------------------------------*/
+/*-------------------------[ EXPLAIN ]---------------------------*/
+/*---------------------------------------------------------------*/
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::{
+    listener::Listener,
+    parsing::quote,
+    request::{Method, Request},
+    server::{
+        globals::Globals,
+        location::{Location, Modifier},
+        parsing::format_duration,
+        server::Server,
+        server_names::{Strict, VirtualHosts},
+        DEFAULT_LISTEN_ADDRESS,
+        traits::{config::Config, handler::Handler},
+    },
+};
+
+/// `webserv explain GET http://host:port/path`: the server, location and
+/// handler the request would get, resolved like `Listener::handle_request`
+/// without opening any port. Paths are checked against the filesystem.
+pub fn explain(servers: &HashMap<SocketAddr, VirtualHosts>, method: &str, url: &str) -> Result<String, String> {
+    let (authority, port, target) = split_url(servers, url)?;
+    let (socket, port_servers) = listen_address(servers, &authority, port)?;
+
+    let raw = format!("{method} {target} HTTP/1.1\r\nHost: {authority}\r\n\r\n");
+    let mut request = match Request::try_from(raw.as_bytes()) {
+        Ok(request) => request,
+        Err(err) => return Err(format!("invalid request: {} {}", err.code(), err.msg())),
+    };
+
+    let (server, captures) = Listener::choose_server_from(&request, port_servers);
+    let mut out = String::new();
+
+    let index = port_servers.servers().iter().position(|serv| std::ptr::eq(&**serv, server)).unwrap_or(0);
+    let _ = write!(out, "server      #{} of {socket}", index + 1);
+    if let Some(names) = server.name() {
+        let _ = write!(out, ", server_name {}", names.join(" "));
+    }
+    if server.is_default() {
+        out.push_str(" (default)");
+    }
+    out.push('\n');
+
+    match (captures, server.strict_server_name()) {
+        (Some(captures), _) => {
+            for (name, value) in &captures {
+                let _ = writeln!(out, "capture     ${name} = {value}");
+            }
+            request.set_server_name_captures(captures);
+        }
+        (None, Strict::Off) => (),
+        (None, Strict::Misdirected) => {
+            out.push_str("handler     421 Misdirected Request: no server_name matches (strict_server_name)\n");
+            return Ok(out);
+        }
+        (None, Strict::Close) => {
+            out.push_str("handler     connection closed: no server_name matches (strict_server_name close)\n");
+            return Ok(out);
+        }
+    }
+
+    let _ = match server.get_request_location(&request) {
+        Some(location) => {
+            let _ = writeln!(out, "location    {} {}", location.modifier(), location.path().display());
+            resolve(&mut out, location, &mut request)
+        }
+        None => {
+            out.push_str("location    none, the server handles the request\n");
+            resolve(&mut out, server, &mut request)
+        }
+    };
+
+    Ok(out)
+}
+
+/// Splits `http://host[:port]/path` into the Host header, the port and the
+/// request target. A bare `/path` goes to the only listen address of the configuration.
+fn split_url(servers: &HashMap<SocketAddr, VirtualHosts>, url: &str) -> Result<(String, u16, String), String> {
+    let rest = match url.split_once("://") {
+        Some(("http", rest)) => rest,
+        Some((scheme, _)) => return Err(format!("{url}: unsupported scheme \"{scheme}\"")),
+        None if url.starts_with('/') => {
+            return match servers.keys().collect::<Vec<_>>()[..] {
+                [socket] => Ok((format!("localhost:{}", socket.port()), socket.port(), url.to_owned())),
+                _ => Err(format!("{url}: several addresses are configured, give http://host:port{url}")),
+            };
+        }
+        None => return Err(format!("{url}: expected http://host[:port]/path or /path")),
+    };
+
+    let (authority, target) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+
+    let port = match authority.rsplit_once(':').filter(|_| !authority.ends_with(']')) {
+        Some((_, port)) => match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => return Err(format!("{url}: invalid port \"{port}\"")),
+        },
+        None => 80,
+    };
+
+    Ok((authority.to_owned(), port, target.to_owned()))
+}
+
+/// The listen address a client reaches through `authority`: the one of its
+/// IP, else the only address of the port, else the default listen address
+fn listen_address<'a>(
+    servers: &'a HashMap<SocketAddr, VirtualHosts>,
+    authority: &str,
+    port: u16,
+) -> Result<(SocketAddr, &'a VirtualHosts), String> {
+    let host = match authority.rsplit_once(':').filter(|_| !authority.ends_with(']')) {
+        Some((host, _)) => host,
+        None => authority,
+    };
+    let ip = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok();
+
+    let candidates: Vec<_> = servers.iter().filter(|(socket, _)| socket.port() == port).collect();
+    let found = match ip {
+        Some(ip) => candidates
+            .iter()
+            .find(|(socket, _)| socket.ip() == ip)
+            .or_else(|| candidates.iter().find(|(socket, _)| socket.ip().is_unspecified() && socket.is_ipv4() == ip.is_ipv4())),
+        None if candidates.len() == 1 => candidates.first(),
+        None => candidates.iter().find(|(socket, _)| socket.ip() == DEFAULT_LISTEN_ADDRESS),
+    };
+
+    match found {
+        Some((socket, hosts)) => Ok((**socket, *hosts)),
+        None => {
+            let mut sockets: Vec<_> = servers.keys().map(SocketAddr::to_string).collect();
+            sockets.sort();
+            Err(format!("no server listens on {host}:{port} (addresses: {})", sockets.join(", ")))
+        }
+    }
+}
+
+/// Same steps as `Handler::handle_request`, reported instead of answered
+fn resolve(out: &mut String, config: &impl Handler, request: &mut Request<'_>) -> std::fmt::Result {
+    out.push_str("directives  (inherited included)\n");
+    write_directives(out, 1, config)?;
+
+    if config.internal() {
+        return writeln!(out, "handler     internal: 404 to clients, served to error_page subrequests");
+    }
+
+    if let Some((code, value)) = config.return_() {
+        return match value {
+            Some(value) => writeln!(out, "handler     return {code} {value}"),
+            None => writeln!(out, "handler     return {code}"),
+        };
+    }
+
+    if let Err(err) = config.parse_method(request) {
+        return writeln!(out, "handler     {} {}", err.code(), err.msg());
+    }
+
+    if let Err(err) = config.format_path(request) {
+        return match err.redirect() {
+            Some(redirect) => writeln!(out, "handler     {} {} to {}", err.code(), err.msg(), redirect.display()),
+            None => writeln!(out, "handler     {} {}", err.code(), err.msg()),
+        };
+    }
+    writeln!(out, "path        {}", request.path().display())?;
+
+    if config.is_cgi(request) {
+        let extension = request.path().extension().unwrap_or_default().to_string_lossy();
+        let interpreter = config.cgi().get(&*extension).map(|path| path.display().to_string());
+        return writeln!(out, "handler     cgi {}", interpreter.unwrap_or_default());
+    }
+
+    match request.method() {
+        Method::POST if config.file_upload() == Some(false) => {
+            writeln!(out, "handler     upload: file_upload off, answered with 403")
+        }
+        Method::POST => match config.upload_folder() {
+            Some(folder) => writeln!(out, "handler     upload into {}", folder.display()),
+            None => writeln!(out, "handler     upload: no upload_folder, answered with 404"),
+        },
+        Method::DELETE => writeln!(out, "handler     delete the file"),
+        _ if request.path().is_dir() => writeln!(out, "handler     autoindex"),
+        _ => writeln!(out, "handler     static file"),
+    }
+}
+
+fn write_directives(out: &mut String, depth: usize, config: &impl Config) -> std::fmt::Result {
+    let indent = "    ".repeat(depth);
+    let mut line = |name: &str, value: String| writeln!(out, "{indent}{name} {value};");
+    let path = |path: &std::path::PathBuf| quote(&path.to_string_lossy());
+
+    // an inherited root does not apply under alias, and cannot be set with it
+    match (config.alias(), config.root()) {
+        (Some(alias), _) => line("alias", path(alias))?,
+        (None, Some(root)) => line("root", path(root))?,
+        (None, None) => (),
+    }
+    if let Some(index) = config.index() {
+        line("index", quote(index))?;
+    }
+    line("auto_index", if config.auto_index() { "on" } else { "off" }.to_owned())?;
+    if let Some(methods) = config.methods() {
+        let methods: Vec<_> = methods.iter().map(Method::to_string).collect();
+        line("allowed_methods", methods.join(" "))?;
+    }
+    if let Some(size) = config.max_body_size() {
+        line("client_max_body_size", size.to_string())?;
+    }
+    if let Some(folder) = config.upload_folder() {
+        line("upload_folder", path(folder))?;
+    }
+    if let Some(on) = config.file_upload() {
+        line("file_upload", if on { "on" } else { "off" }.to_owned())?;
+    }
+    if let Some(charset) = config.charset() {
+        line("charset", quote(charset))?;
+    }
+
+    let mut cgi: Vec<_> = config.cgi().iter().collect();
+    cgi.sort();
+    for (extension, interpreter) in cgi {
+        line("cgi", format!("{} {}", quote(&format!(".{extension}")), path(interpreter)))?;
+    }
+
+    let mut pages: Vec<_> = config.error_pages().iter().collect();
+    pages.sort();
+    for (code, uri) in pages {
+        line("error_page", format!("{code} {}", quote(uri)))?;
+    }
+    let mut redirects: Vec<_> = config.error_redirect().iter().collect();
+    redirects.sort();
+    for (code, (response, uri)) in redirects {
+        match response {
+            Some(response) => line("error_page", format!("{code} ={response} {}", quote(uri)))?,
+            None => line("error_page", format!("{code} = {}", quote(uri)))?,
+        }
+    }
+
+    if let Some(timeout) = config.client_body_timeout() {
+        line("client_body_timeout", format_duration(*timeout))?;
+    }
+    if let Some(timeout) = config.send_timeout() {
+        line("send_timeout", format_duration(*timeout))?;
+    }
+
+    Ok(())
+}
+
+/*---------------------------------------------------------------*/
+/*---------------------------[ DUMP ]----------------------------*/
+/*---------------------------------------------------------------*/
+
+/// `webserv -T`: the configuration the servers were built with, `http`
+/// defaults, inherited directives and default values included, as text the
+/// parser reads back. Servers are sorted by listen address, then in file order,
+/// and upload folders are shown once joined with their location root.
+pub fn dump(globals: &Globals, servers: &HashMap<SocketAddr, VirtualHosts>) -> String {
+    let mut out = String::new();
+    let _ = write_config(&mut out, globals, servers);
+    out
+}
+
+fn write_config(out: &mut String, globals: &Globals, servers: &HashMap<SocketAddr, VirtualHosts>) -> std::fmt::Result {
+    writeln!(out, "worker_connections {};", globals.worker_connections())?;
+    writeln!(out, "pid {};", quote(&globals.pid().to_string_lossy()))?;
+
+    let mut sockets: Vec<_> = servers.iter().collect();
+    sockets.sort_by_key(|(socket, _)| **socket);
+    for (socket, hosts) in sockets {
+        for server in hosts.servers() {
+            out.push('\n');
+            write_server(out, socket, server)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_server(out: &mut String, socket: &SocketAddr, server: &Server) -> std::fmt::Result {
+    out.push_str("server {\n");
+
+    let default = if server.is_default() { " default_server" } else { "" };
+    writeln!(out, "    listen {socket}{default};")?;
+    if let Some(names) = server.name() {
+        let names: Vec<_> = names.iter().map(|name| quote(name)).collect();
+        writeln!(out, "    server_name {};", names.join(" "))?;
+    }
+    match server.strict_server_name() {
+        Strict::Off => (),
+        Strict::Misdirected => out.push_str("    strict_server_name on;\n"),
+        Strict::Close => out.push_str("    strict_server_name close;\n"),
+    }
+    let (per_ip, per_server) = server.limit_conn();
+    if let Some(max) = per_ip {
+        writeln!(out, "    limit_conn ip {max};")?;
+    }
+    if let Some(max) = per_server {
+        writeln!(out, "    limit_conn server {max};")?;
+    }
+
+    let limits = server.header_limits();
+    writeln!(out, "    client_header_timeout {};", format_duration(server.client_header_timeout()))?;
+    writeln!(out, "    keepalive_timeout {};", format_duration(server.keepalive_timeout()))?;
+    writeln!(out, "    keepalive_requests {};", server.keepalive_requests())?;
+    writeln!(out, "    client_header_buffer_size {};", limits.buffer_size)?;
+    writeln!(out, "    large_client_header_buffers {} {};", limits.large_count, limits.line_size)?;
+    writeln!(out, "    client_max_headers {};", limits.max_count)?;
+
+    write_directives(out, 1, server)?;
+    write_handler(out, 1, server)?;
+    for location in server.locations().into_iter().flatten() {
+        out.push('\n');
+        write_location(out, 1, location)?;
+    }
+
+    out.push_str("}\n");
+    Ok(())
+}
+
+fn write_location(out: &mut String, depth: usize, location: &Location) -> std::fmt::Result {
+    let indent = "    ".repeat(depth);
+
+    write!(out, "{indent}location ")?;
+    if location.modifier() != Modifier::Prefix {
+        write!(out, "{} ", location.modifier())?;
+    }
+    writeln!(out, "{} {{", quote(&location.path().to_string_lossy()))?;
+
+    write_directives(out, depth + 1, location)?;
+    write_handler(out, depth + 1, location)?;
+    for nested in location.locations().into_iter().flatten() {
+        out.push('\n');
+        write_location(out, depth + 1, nested)?;
+    }
+
+    writeln!(out, "{indent}}}")
+}
+
+/// `internal` and `return`, which `resolve` reports as the handler
+fn write_handler(out: &mut String, depth: usize, config: &impl Config) -> std::fmt::Result {
+    let indent = "    ".repeat(depth);
+
+    if config.internal() {
+        writeln!(out, "{indent}internal;")?;
+    }
+    match config.return_() {
+        Some((code, Some(value))) => writeln!(out, "{indent}return {code} {};", quote(value)),
+        Some((code, None)) => writeln!(out, "{indent}return {code};"),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    /// `text` built like at startup, `{dir}` standing for a directory of its
+    /// own holding `files` (a trailing `/` makes a directory)
+    async fn build(name: &str, text: &str, files: &[&str]) -> (PathBuf, Globals, HashMap<SocketAddr, VirtualHosts>) {
+        let dir = std::env::temp_dir().join(format!("webserv-explain-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            match file.strip_suffix('/') {
+                Some(path) => fs::create_dir_all(dir.join(path)).unwrap(),
+                None => fs::write(dir.join(file), "").unwrap(),
+            }
+        }
+
+        let path = dir.join("webserv.conf");
+        fs::write(&path, text.replace("{dir}", &dir.to_string_lossy())).unwrap();
+        let (globals, servers) = load(&path).await;
+
+        (dir, globals, servers)
+    }
+
+    async fn load(path: &std::path::Path) -> (Globals, HashMap<SocketAddr, VirtualHosts>) {
+        let config = crate::parsing::get_config(path.to_string_lossy().into_owned(), &HashMap::new()).await;
+        crate::build_config(config.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn dump_is_the_effective_configuration() {
+        let text = "http { root {dir}; keepalive_timeout 90s; send_timeout 1500ms; \
+                    server { listen 9840; location /a/ { allowed_methods GET POST; } } }";
+        let (dir, globals, servers) = build("dump", text, &[]).await;
+
+        let dump = dump(&globals, &servers);
+        assert!(dump.contains("    keepalive_timeout 1m30s;\n"), "{dump}");
+        assert!(dump.contains("    client_header_timeout 1m;\n"), "{dump}");
+        let location = &dump[dump.find("location /a/").unwrap()..];
+        assert!(location.contains(&format!("        root {};\n", dir.display())), "{dump}");
+        assert!(location.contains("        send_timeout 1500ms;\n"), "{dump}");
+
+        // read back, the dump gives the same servers
+        fs::write(dir.join("dump.conf"), &dump).unwrap();
+        let (globals, servers) = load(&dir.join("dump.conf")).await;
+        assert_eq!(super::dump(&globals, &servers), dump);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    const SITE: &str = r#"
+http {
+    root {dir}/www;
+    client_body_timeout 90s;
+    allowed_methods GET;
+
+    server {
+        listen 9850;
+        server_name a.fr;
+
+        location = /exact { return 200 "exact"; }
+        location /private/ { internal; }
+        location /dir/ { auto_index on; }
+        location ~ \.py$ { cgi .py /usr/bin/python3; }
+        location /up/ {
+            allowed_methods GET POST;
+            upload_folder {dir}/uploads;
+            auto_index on;
+            location /up/closed/ { file_upload off; }
+        }
+        location /moved/ {
+            return 301 /elsewhere/;
+            location /moved/kept/ { }
+        }
+    }
+    server { listen 9850; server_name ~^(?<sub>.+)\.b\.fr$; }
+    server { listen 9851; server_name s.fr; strict_server_name on; }
+    server { listen 9852; server_name c.fr; strict_server_name close; }
+}
+"#;
+
+    const FILES: &[&str] = &["www/", "www/dir/", "www/up/", "www/up/closed/", "www/moved/kept/", "www/moved/kept/page.html", "www/page.html", "www/run.py"];
+
+    /// `explain` of `method url` on the servers of `SITE`
+    async fn site(name: &str) -> (PathBuf, impl Fn(&str, &str) -> String) {
+        let (dir, _, servers) = build(name, SITE, FILES).await;
+
+        (dir, move |method: &str, url: &str| explain(&servers, method, url).unwrap())
+    }
+
+    /// The line of `out` starting with `key`, without it
+    fn line<'a>(out: &'a str, key: &str) -> &'a str {
+        match out.lines().find_map(|line| line.strip_prefix(key)) {
+            Some(line) => line.trim(),
+            None => panic!("no \"{key}\" line in:\n{out}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn chooses_the_server_by_name() {
+        let (dir, explain) = site("server").await;
+
+        let out = explain("GET", "http://x.b.fr:9850/page.html");
+        assert_eq!(line(&out, "server"), "#2 of 127.0.0.1:9850, server_name ~^(?<sub>.+)\\.b\\.fr$");
+        assert_eq!(line(&out, "capture"), "$sub = x");
+
+        let out = explain("GET", "http://unknown.fr:9850/page.html");
+        assert_eq!(line(&out, "server"), "#1 of 127.0.0.1:9850, server_name a.fr");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn reports_the_location_and_its_modifier() {
+        let (dir, explain) = site("location").await;
+
+        assert_eq!(line(&explain("GET", "http://a.fr:9850/exact"), "location"), "= /exact");
+        assert_eq!(line(&explain("GET", "http://a.fr:9850/dir/"), "location"), "prefix /dir/");
+        assert_eq!(line(&explain("GET", "http://a.fr:9850/run.py"), "location"), "~ \\.py$");
+        assert_eq!(
+            line(&explain("GET", "http://a.fr:9850/page.html"), "location"),
+            "none, the server handles the request"
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn lists_inherited_directives() {
+        let (dir, explain) = site("inherited").await;
+
+        let out = explain("GET", "http://a.fr:9850/dir/");
+        assert!(out.contains(&format!("    root {}/www;\n", dir.display())), "{out}");
+        assert!(out.contains("    auto_index on;\n"), "{out}");
+        assert!(out.contains("    allowed_methods GET;\n"), "{out}");
+        assert!(out.contains("    client_body_timeout 1m30s;\n"), "{out}");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn reports_each_handler() {
+        let (dir, explain) = site("handler").await;
+
+        for (method, url, handler) in [
+            ("GET", "http://a.fr:9850/exact", "return 200 exact"),
+            ("GET", "http://a.fr:9850/private/x", "internal: 404 to clients, served to error_page subrequests"),
+            ("GET", "http://a.fr:9850/run.py", "cgi /usr/bin/python3"),
+            ("POST", "http://a.fr:9850/up/", "upload into "),
+            ("POST", "http://a.fr:9850/up/closed/", "upload: file_upload off, answered with 403"),
+            ("GET", "http://a.fr:9850/dir/", "autoindex"),
+            ("GET", "http://a.fr:9850/page.html", "static file"),
+            ("GET", "http://a.fr:9850/moved/page.html", "return 301 /elsewhere/"),
+            ("GET", "http://a.fr:9850/moved/kept/page.html", "static file"),
+            ("POST", "http://a.fr:9850/page.html", "405 Method Not Allowed"),
+            ("GET", "http://other.fr:9851/", "421 Misdirected Request: no server_name matches (strict_server_name)"),
+            ("GET", "http://other.fr:9852/", "connection closed: no server_name matches (strict_server_name close)"),
+        ] {
+            let out = explain(method, url);
+            assert!(line(&out, "handler").starts_with(handler), "{method} {url}:\n{out}");
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/*---------------------------------------------------------------*/
+/*-----------------------[ LIMIT CONN ]--------------------------*/
+/*---------------------------------------------------------------*/
+
+/// Tracks the connections a server is serving, per client address and in total,
+/// and refuses new ones past the `limit_conn` thresholds.
+#[derive(Debug, Default)]
+pub struct ConnLimiter {
+    per_ip: Option<usize>,
+    per_server: Option<usize>,
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+#[derive(Debug)]
+pub enum LimitRejection {
+    PerIp(IpAddr, usize),
+    PerServer(usize),
+}
+
+impl fmt::Display for LimitRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitRejection::PerIp(ip, max) => {
+                write!(f, "limit_conn ip: {ip} already has {max} connection(s)")
+            }
+            LimitRejection::PerServer(max) => {
+                write!(f, "limit_conn server: {max} connection(s) already active")
+            }
+        }
+    }
+}
+
+/// Releases its slot in the limiter when dropped.
+pub struct ConnGuard {
+    limiter: Option<Arc<ConnLimiter>>,
+    ip: IpAddr,
+}
+
+impl ConnLimiter {
+    pub fn new(per_ip: Option<usize>, per_server: Option<usize>) -> Self {
+        ConnLimiter {
+            per_ip,
+            per_server,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.per_ip.is_some() || self.per_server.is_some()
+    }
+
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnGuard, LimitRejection> {
+        if !self.is_limited() {
+            return Ok(ConnGuard { limiter: None, ip });
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Some(max) = self.per_server {
+            if state.total >= max {
+                return Err(LimitRejection::PerServer(max));
+            }
+        }
+
+        let by_ip = state.by_ip.get(&ip).copied().unwrap_or(0);
+        if let Some(max) = self.per_ip {
+            if by_ip >= max {
+                return Err(LimitRejection::PerIp(ip, max));
+            }
+        }
+
+        state.total += 1;
+        state.by_ip.insert(ip, by_ip + 1);
+
+        Ok(ConnGuard {
+            limiter: Some(Arc::clone(self)),
+            ip,
+        })
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        state.total = state.total.saturating_sub(1);
+        if let Some(count) = state.by_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.by_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+impl ConnGuard {
+    /// True if the slot was taken in `limiter`
+    pub fn holds(&self, limiter: &Arc<ConnLimiter>) -> bool {
+        self.limiter.as_ref().is_some_and(|held| Arc::ptr_eq(held, limiter))
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        if let Some(limiter) = &self.limiter {
+            limiter.release(self.ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const B: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+    #[test]
+    fn per_ip() {
+        let limiter = Arc::new(ConnLimiter::new(Some(2), None));
+
+        let _first = limiter.try_acquire(A).unwrap();
+        let _second = limiter.try_acquire(A).unwrap();
+        assert!(matches!(limiter.try_acquire(A), Err(LimitRejection::PerIp(ip, 2)) if ip == A));
+        // other addresses have their own count
+        assert!(limiter.try_acquire(B).is_ok());
+    }
+
+    #[test]
+    fn per_server() {
+        let limiter = Arc::new(ConnLimiter::new(None, Some(2)));
+
+        let _first = limiter.try_acquire(A).unwrap();
+        let _second = limiter.try_acquire(B).unwrap();
+        assert!(matches!(limiter.try_acquire(B), Err(LimitRejection::PerServer(2))));
+    }
+
+    #[test]
+    fn slot_is_released_on_drop() {
+        let limiter = Arc::new(ConnLimiter::new(Some(1), Some(1)));
+
+        let guard = limiter.try_acquire(A).unwrap();
+        assert!(guard.holds(&limiter));
+        assert!(limiter.try_acquire(B).is_err());
+
+        drop(guard);
+        assert!(limiter.try_acquire(B).is_ok());
+        assert!(limiter.state.lock().unwrap().by_ip.is_empty());
+    }
+
+    #[test]
+    fn unlimited_holds_nothing() {
+        let limiter = Arc::new(ConnLimiter::default());
+
+        let guards: Vec<_> = (0..10).map(|_| limiter.try_acquire(A).unwrap()).collect();
+        assert!(guards.iter().all(|guard| !guard.holds(&limiter)));
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/*---------------------------------------------------------------*/
+/*-------------------------[ METRICS ]---------------------------*/
+/*---------------------------------------------------------------*/
+
+/// Process-wide connection counters, reported on SIGUSR2 (`-s status`) and
+/// when the server shuts down.
+pub struct Metrics {
+    pub accepted: AtomicUsize,
+    pub active: AtomicUsize,
+    pub accept_paused: AtomicUsize,
+    pub rejected_per_ip: AtomicUsize,
+    pub rejected_per_server: AtomicUsize,
+}
+
+pub static METRICS: Metrics = Metrics {
+    accepted: AtomicUsize::new(0),
+    active: AtomicUsize::new(0),
+    accept_paused: AtomicUsize::new(0),
+    rejected_per_ip: AtomicUsize::new(0),
+    rejected_per_server: AtomicUsize::new(0),
+};
+
+impl Metrics {
+    pub fn incr(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decr(counter: &AtomicUsize) {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> String {
+        format!(
+            "accepted={} active={} accept_paused={} rejected_per_ip={} rejected_per_server={}",
+            self.accepted.load(Ordering::Relaxed),
+            self.active.load(Ordering::Relaxed),
+            self.accept_paused.load(Ordering::Relaxed),
+            self.rejected_per_ip.load(Ordering::Relaxed),
+            self.rejected_per_server.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -1,58 +1,187 @@
+pub mod limits;
+pub mod metrics;
+pub mod stream;
+
 use std::{
     collections::HashMap,
     io::{self},
-    net::IpAddr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
 };
 
+use arc_swap::ArcSwap;
 use colored::Colorize;
+use limits::{ConnGuard, LimitRejection};
+use metrics::{Metrics, METRICS};
+use stream::ClientStream;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::{JoinError, JoinSet},
+    time::{sleep, timeout, timeout_at, Instant},
 };
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    request::{Method, Request},
+    request::{parser::RequestParser, Method, Request, State},
     response::response::{Response, ResponseCode},
     server::{
         server::Server,
+        server_names::{Captures, Strict, VirtualHosts},
+        DEFAULT_SEND_TIMEOUT,
         traits::{
-            config::{utils::{self}, Config},
+            config::Config,
             handler::Handler,
         },
     },
 };
 
+/// Pause after a failed accept (EMFILE, ECONNABORTED...) before the next one
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
 pub struct Listener {
     listener: TcpListener,
-    servers: Vec<Server>,
+    servers: Arc<ArcSwap<Bound>>,
     cancel_token: CancellationToken,
+    connections: Arc<Semaphore>,
 }
 
-impl Listener {
+/// Every listen address with the servers it dispatches to. On reload the
+/// servers of a kept address are swapped (connections in progress finish with
+/// the ones they started with), new addresses are bound and removed ones closed.
+pub struct Listeners {
+    connections: Arc<Semaphore>,
+    cancel_token: CancellationToken,
+    sockets: HashMap<SocketAddr, Socket>,
+    tasks: JoinSet<io::Result<()>>,
+}
+
+struct Socket {
+    servers: Arc<ArcSwap<Bound>>,
+    cancel_token: CancellationToken,
+}
+
+/// The servers of a bound socket. Like nginx, a port listened to on every
+/// address (`listen *:8080;`) is the only one bound: the addresses of that
+/// port listened to on their own (`listen 127.0.0.1:8080;`) are told apart
+/// by the local address of each connection.
+#[derive(Default)]
+pub struct Bound {
+    hosts: VirtualHosts,
+    addresses: HashMap<IpAddr, VirtualHosts>,
+}
+
+impl Bound {
+    /// The listen addresses grouped by socket to bind
+    fn group(servers: HashMap<SocketAddr, VirtualHosts>) -> HashMap<SocketAddr, Bound> {
+        let wildcard = |socket: &SocketAddr| match socket {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), socket.port()),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), socket.port()),
+        };
+        let (wildcards, addresses): (Vec<_>, Vec<_>) =
+            servers.into_iter().partition(|(socket, _)| socket.ip().is_unspecified());
+
+        let mut bound: HashMap<SocketAddr, Bound> = wildcards
+            .into_iter()
+            .map(|(socket, hosts)| (socket, Bound { hosts, addresses: HashMap::new() }))
+            .collect();
+        for (socket, hosts) in addresses {
+            if let Some(port) = bound.get_mut(&wildcard(&socket)) {
+                port.addresses.insert(socket.ip(), hosts);
+            } else {
+                bound.insert(socket, Bound { hosts, addresses: HashMap::new() });
+            }
+        }
+
+        bound
+    }
+
+    /// The servers of the address a connection reached
+    fn hosts(&self, local: Option<SocketAddr>) -> &VirtualHosts {
+        match local.and_then(|local| self.addresses.get(&local.ip())) {
+            Some(hosts) => hosts,
+            None => &self.hosts,
+        }
+    }
+}
+
+impl Listeners {
     pub async fn init_listeners(
-        addr: IpAddr,
-        servers: HashMap<u16, Vec<Server>>,
+        servers: HashMap<SocketAddr, VirtualHosts>,
+        connections: &Arc<Semaphore>,
         cancel_token: &CancellationToken,
-    ) -> io::Result<Vec<Self>> {
-        let mut listeners: Vec<Self> = Vec::new();
+    ) -> io::Result<Self> {
+        let mut listeners = Listeners {
+            connections: Arc::clone(connections),
+            cancel_token: cancel_token.clone(),
+            sockets: HashMap::new(),
+            tasks: JoinSet::new(),
+        };
 
-        for (port, vec) in servers {
-            let listener = Self::new(addr, port, vec, cancel_token.clone()).await?;
-            listeners.push(listener);
-        }
+        listeners.reload(servers).await?;
 
         Ok(listeners)
     }
 
+    /// New addresses are all bound before anything changes: if one of them
+    /// fails, the running configuration is left untouched.
+    pub async fn reload(&mut self, servers: HashMap<SocketAddr, VirtualHosts>) -> io::Result<()> {
+        let servers = Bound::group(servers);
+
+        let mut added = HashMap::new();
+        for socket in servers.keys().filter(|socket| !self.sockets.contains_key(socket)) {
+            let socket_servers = Arc::new(ArcSwap::from_pointee(Bound::default()));
+            let cancel_token = self.cancel_token.child_token();
+            let listener = Listener::new(
+                *socket,
+                Arc::clone(&socket_servers),
+                Arc::clone(&self.connections),
+                cancel_token.clone(),
+            )
+            .await
+            .map_err(|err| io::Error::new(err.kind(), format!("{socket}: {err}")))?;
+            added.insert(*socket, (listener, Socket { servers: socket_servers, cancel_token }));
+        }
+
+        let removed: Vec<SocketAddr> = self
+            .sockets
+            .keys()
+            .filter(|socket| !servers.contains_key(socket))
+            .copied()
+            .collect();
+        for socket in removed {
+            if let Some(socket) = self.sockets.remove(&socket) {
+                socket.cancel_token.cancel();
+            }
+        }
+
+        for (socket, hosts) in servers {
+            if let Some((listener, new_socket)) = added.remove(&socket) {
+                new_socket.servers.store(Arc::new(hosts));
+                self.sockets.insert(socket, new_socket);
+                self.tasks.spawn(listener.listen());
+            } else if let Some(kept) = self.sockets.get(&socket) {
+                kept.servers.store(Arc::new(hosts));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn join_next(&mut self) -> Option<Result<io::Result<()>, JoinError>> {
+        self.tasks.join_next().await
+    }
+}
+
+impl Listener {
     pub async fn new(
-        addr: IpAddr,
-        port: u16,
-        servers: Vec<Server>,
+        socket: SocketAddr,
+        servers: Arc<ArcSwap<Bound>>,
+        connections: Arc<Semaphore>,
         cancel_token: CancellationToken,
     ) -> io::Result<Self> {
-        let socket = format!("{}:{}", addr, port);
-
         let listener = match TcpListener::bind(socket).await {
             Ok(listener) => listener,
             Err(err) => return Err(err),
@@ -62,6 +191,7 @@ impl Listener {
             servers,
             listener,
             cancel_token,
+            connections,
         })
     }
 
@@ -77,8 +207,34 @@ impl Listener {
 
         loop {
             let cancel = self.cancel_token.clone();
+
+            // worker_connections: stop accepting until a connection slot is released
+            let permit = match self.acquire_connection_slot(&cancel).await {
+                Some(permit) => permit,
+                None => {
+                    self.stop_listening();
+                    return Ok(());
+                }
+            };
+
             tokio::select! {
-                Ok((stream, addr)) = self.listener.accept() => {
+                res = self.listener.accept() => {
+                    let (stream, addr) = match res {
+                        Ok(accepted) => accepted,
+                        // the listener stays usable: retry once a descriptor may have been freed
+                        Err(err) => {
+                            eprintln!("{}", format!("Error: accept: {err}").yellow());
+                            drop(permit);
+                            tokio::select! {
+                                _ = sleep(ACCEPT_BACKOFF) => continue,
+                                _ = cancel.cancelled() => {
+                                    self.stop_listening();
+                                    return Ok(());
+                                }
+                            }
+                        }
+                    };
+
                     println!(
 						"{}",
 						format!(
@@ -88,73 +244,177 @@ impl Listener {
 						.bright_black()
 						.bold()
 					);
-                    let server_instance = self.servers.clone();
+                    Metrics::incr(&METRICS.accepted);
+                    Metrics::incr(&METRICS.active);
+                    // the connection keeps this configuration even if it is reloaded meanwhile
+                    let server_instance = self.servers.load_full();
                     tokio::spawn( async move {
-                        let _ = Self::handle_stream(stream, &server_instance).await;
+                        let local = stream.local_addr().ok();
+                        let _ = Self::handle_stream(stream, addr, server_instance.hosts(local)).await;
+                        Metrics::decr(&METRICS.active);
+                        drop(permit);
                     });
                 }
                 _ = cancel.cancelled() => {
-                    println!("{}",
-						format!(
-							"------[listener ({}): stop listening]------",
-							self.listener.local_addr().unwrap().to_string().italic()
-						)
-						.bright_black()
-						.bold()
-					);
+                    self.stop_listening();
 					return Ok(());
                 }
             }
         }
     }
 
-    async fn handle_stream(mut stream: TcpStream, servers: &Vec<Server>) -> anyhow::Result<()> {
-        let mut raw = Vec::new();
+    async fn acquire_connection_slot(
+        &self,
+        cancel: &CancellationToken,
+    ) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = Arc::clone(&self.connections).try_acquire_owned() {
+            return Some(permit);
+        }
+
+        Metrics::incr(&METRICS.accept_paused);
+        eprintln!(
+            "{}",
+            format!(
+                "------[listener ({}): worker_connections reached, accept paused]------",
+                self.listener.local_addr().unwrap().to_string().italic()
+            )
+            .yellow()
+            .bold()
+        );
+
+        tokio::select! {
+            permit = Arc::clone(&self.connections).acquire_owned() => permit.ok(),
+            _ = cancel.cancelled() => None,
+        }
+    }
+
+    fn stop_listening(&self) {
+        println!("{}",
+			format!(
+				"------[listener ({}): stop listening]------",
+				self.listener.local_addr().unwrap().to_string().italic()
+			)
+			.bright_black()
+			.bold()
+		);
+    }
+
+    async fn handle_stream(
+        stream: TcpStream,
+        addr: SocketAddr,
+        servers: &VirtualHosts,
+    ) -> anyhow::Result<()> {
+        // before the Host header is known, the default server's settings apply;
+        // between requests, those of the server that handled the last one
+        let default = servers.default_server();
+        let limits = default.header_limits();
+        let mut server = default;
+        // limit_conn: slot of the server handling the connection, held until it closes
+        let mut slot: Option<ConnGuard> = None;
+
+        let mut stream = ClientStream::new(stream);
+        let mut parser = RequestParser::with_capacity(limits.buffer_size);
         let mut buffer = [0; 8196];
+        let mut requests = 0;
+        let mut header_deadline: Option<Instant> = None;
+
+        stream.set_write_timeout(Some(
+            default.send_timeout().copied().unwrap_or(DEFAULT_SEND_TIMEOUT),
+        ));
 
         loop {
-            let n = match stream.read(&mut buffer).await? {
-                0 => return Ok(()),
-                n => n,
+            stream.set_read_timeout(None);
+
+            let read = match header_deadline {
+                // inside a header: client_header_timeout covers the whole header
+                Some(deadline) => timeout_at(deadline, stream.read(&mut buffer)).await,
+                // between requests: keepalive_timeout
+                None if requests > 0 => {
+                    timeout(server.keepalive_timeout(), stream.read(&mut buffer)).await
+                }
+                None => timeout(server.client_header_timeout(), stream.read(&mut buffer)).await,
+            };
+
+            let n = match read {
+                Ok(read) => match read? {
+                    0 => return Ok(()),
+                    n => n,
+                },
+                Err(_) => {
+                    if parser.state().is(State::OnHeader) {
+                        eprintln!("{}", format!("Timeout {addr}: client_header_timeout").yellow());
+                        stream.set_closing(true);
+                        server
+                            .send_error(&mut stream, ResponseCode::from_code(408), &mut buffer)
+                            .await;
+                    }
+                    let _ = stream.shutdown().await;
+                    return Ok(());
+                }
             };
 
-            raw.extend_from_slice(&buffer[..n]);
-
-            while let Some(delim) = utils::find_in(raw.as_slice(), b"\r\n\r\n") {
-                let header = &raw[..delim + 2];
-                let raw_left = &raw[delim + 4..];
-                raw = match Self::handle_request(
-                    header,
-                    &mut stream,
-                    servers,
-                    &mut raw_left.to_owned(),
-                    &mut buffer,
-                )
-                .await
-                {
-                    Some(raw_left) => raw_left,
+            parser.feed(&buffer[..n]);
+            if header_deadline.is_none() {
+                header_deadline = Some(Instant::now() + server.client_header_timeout());
+            }
+
+            loop {
+                match parser.advance(&limits) {
+                    Ok(true) => (),
+                    Ok(false) => break,
+                    Err(code) => {
+                        eprintln!("{}", format!("Rejected {addr}: invalid header ({})", code.code()).yellow());
+                        stream.set_closing(true);
+                        default.send_error(&mut stream, code, &mut buffer).await;
+                        let _ = stream.shutdown().await;
+                        return Ok(());
+                    }
+                }
+
+                match Self::handle_request(&mut parser, &mut stream, addr, servers, requests, &mut slot, &mut buffer).await {
+                    Some((raw_left, chosen)) => {
+                        parser.reset(raw_left);
+                        server = chosen;
+                    }
                     None => {
-						let _ = stream.shutdown();
+						let _ = stream.shutdown().await;
 						return Ok(())
 					},
+                };
+
+                requests += 1;
+                if stream.closing() {
+                    let _ = stream.shutdown().await;
+                    return Ok(());
                 }
+
+                header_deadline = match parser.state().is(State::Undefined) {
+                    true => None,
+                    false => Some(Instant::now() + server.client_header_timeout()),
+                };
             }
         }
     }
 
-    async fn handle_request(
-        header: &[u8],
-        stream: &mut TcpStream,
-        servers: &Vec<Server>,
-        raw_left: &mut [u8],
+    /// The bytes left for the next request, and the server that handled this one.
+    /// `requests`: the requests already handled on the connection.
+    async fn handle_request<'s>(
+        parser: &mut RequestParser,
+        stream: &mut ClientStream,
+        addr: SocketAddr,
+        servers: &'s VirtualHosts,
+        requests: usize,
+        slot: &mut Option<ConnGuard>,
         buffer: &mut [u8; 8196],
-    ) -> Option<Vec<u8>> {
-        let request = match Request::try_from(header) {
+    ) -> Option<(Vec<u8>, &'s Server)> {
+        let (head, raw_left) = parser.split();
+        let mut request = match Request::try_from(&head) {
             Ok(request) => request,
             Err(err) => {
                 eprintln!("Error: deserializing header: {}", err.to_string());
-                send_error_response(stream, err, buffer).await;
-                return Some(raw_left.to_vec());
+                stream.set_closing(true);
+                servers.default_server().send_error(stream, err, buffer).await;
+                return None; // the body framing cannot be trusted, close the connection
             }
         };
 
@@ -165,7 +425,47 @@ impl Listener {
         //     request.path().display().to_string().italic().bright_red(),
         // );
 
-        let server = Self::choose_server_from(&request, servers);
+        let (server, captures) = Self::choose_server_from(&request, servers);
+
+        // last response of the connection: asked by the client, keepalive_requests
+        // reached or keep-alive off
+        stream.set_closing(
+            !request.keep_connection_alive()
+                || requests + 1 >= server.keepalive_requests()
+                || server.keepalive_timeout().is_zero(),
+        );
+
+        match (captures, server.strict_server_name()) {
+            (Some(captures), _) => request.set_server_name_captures(captures),
+            (None, Strict::Off) => (),
+            (None, strict) => {
+                eprintln!("{}", format!("Rejected {addr}: unknown host {:?}", request.host()).yellow());
+                stream.set_closing(true);
+                if strict == Strict::Misdirected {
+                    server.send_error(stream, ResponseCode::from_code(421), buffer).await;
+                }
+                return None;
+            }
+        }
+
+        // limit_conn: the slot is taken by the first request, and again when a
+        // keep-alive request is handled by another server
+        if !slot.as_ref().is_some_and(|guard| guard.holds(server.limiter())) {
+            *slot = None;
+            match server.limiter().try_acquire(addr.ip()) {
+                Ok(guard) => *slot = Some(guard),
+                Err(rejection) => {
+                    match rejection {
+                        LimitRejection::PerIp(..) => Metrics::incr(&METRICS.rejected_per_ip),
+                        LimitRejection::PerServer(_) => Metrics::incr(&METRICS.rejected_per_server),
+                    }
+                    eprintln!("{}", format!("Rejected {addr}: {rejection}").yellow());
+                    stream.set_closing(true);
+                    server.send_error(stream, ResponseCode::from_code(503), buffer).await;
+                    return None;
+                }
+            }
+        }
 
         let raw_left = if let Some(location) = server.get_request_location(&request) {
 
@@ -174,40 +474,18 @@ impl Listener {
             server.handle_request(request, stream, raw_left, buffer).await
         };
 
-        return raw_left;
+        raw_left.map(|raw_left| (raw_left, server))
     }
 
-    fn choose_server_from<'a>(request: &Request, servers: &'a Vec<Server>) -> &'a Server {
-        let mut default = None;
-
-        if request.host().is_some() {
-            let hostname = request.host().unwrap();
-            for serv in servers {
-                if serv.is_default() {
-                    default = Some(serv)
-                }
-                if serv.name().is_none() {
-                    continue;
-                }
-
-                let names = serv.name().unwrap();
-
-                if names.iter().any(|name| name == hostname) {
-                    return serv;
-                }
-            }
-        }
-
-        if default.is_some() {
-            return default.unwrap();
-        }
-
-        return servers.first().unwrap();
+    /// server_name of the Host header (lowercase, without port), see `ServerNames::find`.
+    /// No captures: no server_name matched, the default server was chosen.
+    pub fn choose_server_from<'a>(request: &Request<'_>, servers: &'a VirtualHosts) -> (&'a Server, Option<Captures>) {
+        servers.find(request.hostname().as_deref())
     }
 }
 
 pub async fn send_error_response(
-    stream: &mut TcpStream,
+    stream: &mut ClientStream,
     code: ResponseCode,
     buffer: &mut [u8; 8196],
 ) {
@@ -215,3 +493,24 @@ pub async fn send_error_response(
 
     let _ = response.send(stream, buffer).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_sockets_take_in_the_addresses_of_their_port() {
+        let servers = ["0.0.0.0:8080", "127.0.0.1:8080", "127.0.0.1:8081", "[::1]:8080"]
+            .map(|socket| (socket.parse().unwrap(), VirtualHosts::default()));
+        let bound = Bound::group(HashMap::from(servers));
+
+        let mut sockets: Vec<_> = bound.keys().map(SocketAddr::to_string).collect();
+        sockets.sort();
+        assert_eq!(sockets, ["0.0.0.0:8080", "127.0.0.1:8081", "[::1]:8080"]);
+
+        let port = &bound[&"0.0.0.0:8080".parse().unwrap()];
+        let loopback = &port.addresses[&"127.0.0.1".parse().unwrap()];
+        assert!(std::ptr::eq(port.hosts(Some("127.0.0.1:8080".parse().unwrap())), loopback));
+        assert!(std::ptr::eq(port.hosts(Some("127.0.0.3:8080".parse().unwrap())), &port.hosts));
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    future::Future,
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::{sleep, Sleep},
+};
+
+/*---------------------------------------------------------------*/
+/*----------------------[ CLIENT STREAM ]------------------------*/
+/*---------------------------------------------------------------*/
+
+/// Client connection whose reads and writes fail with `ErrorKind::TimedOut`
+/// when no progress is made for longer than the configured timeout
+/// (`client_body_timeout` / `send_timeout` semantics).
+#[derive(Debug)]
+pub struct ClientStream {
+    inner: TcpStream,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    read_deadline: Option<Pin<Box<Sleep>>>,
+    write_deadline: Option<Pin<Box<Sleep>>>,
+    closing: bool,
+}
+
+impl ClientStream {
+    pub fn new(inner: TcpStream) -> Self {
+        ClientStream {
+            inner,
+            read_timeout: None,
+            write_timeout: None,
+            read_deadline: None,
+            write_deadline: None,
+            closing: false,
+        }
+    }
+
+    /// The connection closes after the response being sent, which tells the
+    /// client so with `Connection: close`
+    pub fn set_closing(&mut self, closing: bool) {
+        self.closing = closing;
+    }
+
+    pub fn closing(&self) -> bool {
+        self.closing
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+        self.read_deadline = None;
+    }
+
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+        self.write_deadline = None;
+    }
+
+    fn poll_deadline<T>(
+        deadline: &mut Option<Pin<Box<Sleep>>>,
+        timeout: Option<Duration>,
+        cx: &mut Context<'_>,
+        msg: &str,
+    ) -> Poll<io::Result<T>> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+
+        let sleep = deadline.get_or_insert_with(|| Box::pin(sleep(timeout)));
+
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                *deadline = None;
+                Poll::Ready(Err(io::Error::new(ErrorKind::TimedOut, msg)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(res) => {
+                this.read_deadline = None;
+                Poll::Ready(res)
+            }
+            Poll::Pending => Self::poll_deadline(
+                &mut this.read_deadline,
+                this.read_timeout,
+                cx,
+                "client timed out while sending",
+            ),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(res) => {
+                this.write_deadline = None;
+                Poll::Ready(res)
+            }
+            Poll::Pending => Self::poll_deadline(
+                &mut this.write_deadline,
+                this.write_timeout,
+                cx,
+                "client timed out while receiving",
+            ),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
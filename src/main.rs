@@ -1,3 +1,5 @@
+mod cli;
+mod explain;
 mod listener;
 #[allow(dead_code)]
 mod parsing;
@@ -7,106 +9,265 @@ mod request;
 mod response;
 mod server;
 
-use listener::Listener;
+use cli::{Action, Args, EXIT_CONFIG, EXIT_OK, EXIT_SIGNAL, EXIT_STARTUP, EXIT_USAGE};
+use colored::Colorize;
+use listener::{metrics::METRICS, Listeners};
 use parsing::*;
-use server::server::Server;
-use std::{env, net::IpAddr};
-use tokio::{signal, task::JoinSet};
+use server::{globals::Globals, server::Server, server_names::VirtualHosts};
+use std::{collections::HashMap, env, net::SocketAddr, process::ExitCode, sync::Arc};
+use tokio::{
+    signal::{
+        self,
+        unix::{signal as unix_signal, SignalKind},
+    },
+    sync::Semaphore,
+};
 use tokio_util::sync::CancellationToken;
 
-fn listen_signals(cancel_token: &CancellationToken) {
+/// Ctrl-C and SIGTERM (`-s stop`) stop the listeners
+fn listen_signals(cancel_token: &CancellationToken) -> std::io::Result<()> {
+    let mut terminate = unix_signal(SignalKind::terminate())?;
+
     tokio::spawn({
         let cancel_token = cancel_token.clone();
         async move {
-            if let Ok(()) = signal::ctrl_c().await {
-                println!(" Server shutdown");
-                cancel_token.cancel();
+            tokio::select! {
+                Ok(()) = signal::ctrl_c() => (),
+                Some(()) = terminate.recv() => (),
             }
+            println!(" Server shutdown");
+            cancel_token.cancel();
         }
     });
+
+    Ok(())
 }
 
-fn get_args() -> Result<(Option<String>, bool), String> {
-    let args = env::args();
-    let mut option_t = false;
-    let mut config = None;
+/// Parses and validates the whole configuration, at startup and on reload
+async fn load_config(
+    config_file: &str,
+    defines: &HashMap<String, String>,
+) -> Result<(Globals, HashMap<SocketAddr, VirtualHosts>), String> {
+    build_config(parsing::get_config(config_file.to_owned(), defines).await?)
+}
+
+fn build_config(config: MainBlock) -> Result<(Globals, HashMap<SocketAddr, VirtualHosts>), String> {
+    let globals = Globals::new(config.directives)?;
+
+    let servers = Server::init_servers(config.servers)?;
+
+    let servers = match Server::parse_servers(servers) {
+        Ok(map) => map,
+        Err(err) => return Err(format!("parsing: {err}")),
+    };
 
-    if args.len() > 3 {
-        return Err("Error: too many arguments".to_owned());
+    if servers.is_empty() {
+        return Err("no server block".to_owned());
     }
 
-    let mut i = 0;
-    for arg in args {
-        if i == 0 {
-            i += 1;
-            continue;
-        }
-        if arg == "t" {
-            if option_t == true {
-                eprintln!("Warning: duplicate option")
-            } else {
-                option_t = true
-            }
-        } else {
-            config = Some(arg);
+    Ok((globals, servers))
+}
+
+/// SIGHUP: the new configuration replaces the running one only once it is
+/// entirely valid and its new ports are bound, otherwise nothing changes.
+async fn reload(
+    config_file: &str,
+    defines: &HashMap<String, String>,
+    globals: &Globals,
+    listeners: &mut Listeners,
+) {
+    println!("{}", "------[SIGHUP: reloading configuration]------".bright_black().bold());
+
+    let (new_globals, servers) = match load_config(config_file, defines).await {
+        Ok(config) => config,
+        Err(err) => return eprintln!("{}", format!("Error: reload failed, keeping the current configuration: {err}").red()),
+    };
+
+    if let Err(err) = Server::create_upload_folders(&servers) {
+        return eprintln!("{}", format!("Error: reload failed, keeping the current configuration: {err}").red());
+    }
+
+    if let Err(err) = listeners.reload(servers).await {
+        return eprintln!("{}", format!("Error: reload failed, keeping the current configuration: {err}").red());
+    }
+
+    if new_globals.worker_connections() != globals.worker_connections() {
+        eprintln!("{}", "Warning: reload: worker_connections only changes on restart".yellow());
+    }
+
+    println!("{}", "------[configuration reloaded]------".green().bold());
+}
+
+/// `-s`: the pid file is the one of the configuration, or the default one
+/// when the configuration cannot be read
+async fn send_signal(args: &Args, signal: cli::Signal) -> ExitCode {
+    let globals = match parsing::get_config(args.config.clone(), &args.defines).await {
+        Ok(config) => Globals::new(config.directives),
+        Err(err) => Err(err),
+    };
+    let globals = globals.unwrap_or_else(|err| {
+        eprintln!("{}", format!("Warning: {err}, using the default pid file").yellow());
+        Globals::default()
+    });
+
+    match signal.send(globals.pid()) {
+        Ok(()) => ExitCode::from(EXIT_OK),
+        Err(err) => {
+            eprintln!("Error: -s: {err}");
+            ExitCode::from(EXIT_SIGNAL)
+        }
+    }
+}
+
+/// `fmt`: the file is only replaced once the new text is entirely written
+fn format_config(config_file: &str, flatten: bool) -> ExitCode {
+    let formatted = match parsing::format_config(config_file, flatten) {
+        Ok(formatted) => formatted,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            return ExitCode::from(EXIT_CONFIG);
         }
+    };
+
+    let temporary = format!("{config_file}.fmt");
+    let written = std::fs::write(&temporary, formatted).and_then(|()| std::fs::rename(&temporary, config_file));
+    if let Err(err) = written {
+        let _ = std::fs::remove_file(&temporary);
+        eprintln!("Error: fmt: {config_file}: {err}");
+        return ExitCode::from(EXIT_STARTUP);
     }
 
-    Ok((config, option_t))
+    ExitCode::from(EXIT_OK)
 }
 
 #[tokio::main]
-async fn main() {
-    let (config_file, option_t) = match get_args() {
-        Ok(res) => res,
-        Err(err) => return eprintln!("{err}"),
+async fn main() -> ExitCode {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error: {err}\nTry 'webserv --help' for more information.");
+            return ExitCode::from(EXIT_USAGE);
+        }
     };
 
-    let config_file = if config_file.is_some() {
-        config_file.unwrap()
-    } else {
-        "default.conf".to_owned()
-    };
+    match args.action {
+        Action::Help => {
+            println!("{}", cli::USAGE);
+            return ExitCode::from(EXIT_OK);
+        }
+        Action::Version => {
+            println!("webserv version {}", env!("CARGO_PKG_VERSION"));
+            return ExitCode::from(EXIT_OK);
+        }
+        Action::Schema => {
+            println!("{:#}", parsing::schema());
+            return ExitCode::from(EXIT_OK);
+        }
+        _ => (),
+    }
 
-    let cancel_token = CancellationToken::new();
+    if let Some(prefix) = &args.prefix {
+        if let Err(err) = env::set_current_dir(prefix) {
+            eprintln!("Error: -p {prefix}: {err}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+    }
 
-    let config = match parsing::get_config(config_file).await {
+    if let Action::Signal(signal) = args.action {
+        return send_signal(&args, signal).await;
+    }
+
+    if let Action::Format { flatten } = args.action {
+        return format_config(&args.config, flatten);
+    }
+
+    let config = match parsing::get_config(args.config.clone(), &args.defines).await {
         Ok(config) => config,
-        Err(err) => return eprintln!("Error: {err}"),
+        Err(err) => {
+            eprintln!("Error: {err}");
+            return ExitCode::from(EXIT_CONFIG);
+        }
     };
-
-    let servers = match Server::init_servers(config) {
-        Ok(vec) => vec,
-        Err(e) => return eprintln!("Error: {}", e),
+    let (globals, servers) = match build_config(config) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            return ExitCode::from(EXIT_CONFIG);
+        }
     };
 
-    let servers = match Server::parse_servers(servers) {
-        Ok(map) => map,
-        Err(err) => return eprintln!("Error: parsing: {err}"),
-    };
+    match &args.action {
+        Action::Test => {
+            println!("----[Parsing réussi !]----");
+            return ExitCode::from(EXIT_OK);
+        }
+        Action::Dump => {
+            print!("{}", explain::dump(&globals, &servers));
+            return ExitCode::from(EXIT_OK);
+        }
+        Action::Explain { method, url } => {
+            return match explain::explain(&servers, method, url) {
+                Ok(explanation) => {
+                    print!("{explanation}");
+                    ExitCode::from(EXIT_OK)
+                }
+                Err(err) => {
+                    eprintln!("Error: explain: {err}");
+                    ExitCode::from(EXIT_USAGE)
+                }
+            };
+        }
+        _ => (),
+    }
+
+    if let Err(err) = Server::create_upload_folders(&servers) {
+        eprintln!("Error: {err}");
+        return ExitCode::from(EXIT_STARTUP);
+    }
 
-    if option_t == true { return println!("----[Parsing réussi !]----") } // parsing fini
-    ;
+    let cancel_token = CancellationToken::new();
 
-    let addr = IpAddr::from([127, 0, 0, 1]);
-    let listeners = match Listener::init_listeners(addr, servers, &cancel_token).await {
+    let connections = Arc::new(Semaphore::new(globals.worker_connections()));
+    let mut listeners = match Listeners::init_listeners(servers, &connections, &cancel_token).await {
         Ok(listeners) => listeners,
-        Err(err) => return eprintln!("Error: {err}"),
+        Err(err) => {
+            eprintln!("Error: {err}");
+            return ExitCode::from(EXIT_STARTUP);
+        }
     };
 
-    listen_signals(&cancel_token);
-
-    let mut task = JoinSet::new();
-    for serv in listeners {
-        task.spawn(serv.listen());
+    if let Err(err) = std::fs::write(globals.pid(), format!("{}\n", std::process::id())) {
+        eprintln!("Error: pid file {}: {err}", globals.pid().display());
+        return ExitCode::from(EXIT_STARTUP);
     }
 
-    while let Some(res) = task.join_next().await {
-        match res {
-            Err(e) => {
-                eprintln!("----[Error: {e}]----")
-            }
-            Ok(_) => {}
+    let signals = listen_signals(&cancel_token)
+        .and_then(|()| unix_signal(SignalKind::hangup()))
+        .and_then(|hangup| Ok((hangup, unix_signal(SignalKind::user_defined1())?)))
+        .and_then(|(hangup, reopen)| Ok((hangup, reopen, unix_signal(SignalKind::user_defined2())?)));
+    let (mut hangup, mut reopen, mut status) = match signals {
+        Ok(signals) => signals,
+        Err(err) => {
+            eprintln!("Error: signals: {err}");
+            return ExitCode::from(EXIT_STARTUP);
+        }
+    };
+
+    loop {
+        tokio::select! {
+            res = listeners.join_next() => match res {
+                None => break,
+                Some(Err(e)) => eprintln!("----[Error: {e}]----"),
+                Some(Ok(_)) => {}
+            },
+            Some(()) = hangup.recv() => reload(&args.config, &args.defines, &globals, &mut listeners).await,
+            // logs only go to stdout / stderr, whoever redirects them owns the files
+            Some(()) = reopen.recv() => println!("{}", "------[SIGUSR1: no log file to reopen]------".bright_black().bold()),
+            Some(()) = status.recv() => println!("----[Connections: {}]----", METRICS.report()),
         }
     }
+
+    let _ = std::fs::remove_file(globals.pid());
+    println!("----[Connections: {}]----", METRICS.report());
+    ExitCode::from(EXIT_OK)
 }
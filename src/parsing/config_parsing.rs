@@ -1,219 +1,627 @@
 extern crate nom;
 
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, env, path::PathBuf};
 
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while},
-    character::complete::{char, multispace0, space1},
+    character::complete::{char, multispace0, multispace1, not_line_ending},
+    combinator::{recognize, value},
+    error::ErrorKind,
     multi::many0,
-    sequence::preceded,
+    sequence::pair,
     IResult,
 };
 
-use crate::LocationBlock;
+use crate::{
+    server::directives::{self, Context, Directive},
+    LocationBlock,
+};
+
+use super::{MainBlock, ServerBlock};
+
+// Erreur de parsing : un message et la position (le reste de l'entrée) où elle se produit
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError<'a> {
+    pub input: &'a str,
+    pub message: String,
+}
+
+impl<'a> nom::error::ParseError<&'a str> for ParseError<'a> {
+    fn from_error_kind(input: &'a str, _: ErrorKind) -> Self {
+        ParseError {
+            input,
+            message: unexpected(input),
+        }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+type Res<'a, T> = IResult<&'a str, T, ParseError<'a>>;
+
+// Erreur récupérable : l'alternative suivante est essayée
+fn reject<T>(input: &str) -> Res<'_, T> {
+    Err(nom::Err::Error(ParseError {
+        input,
+        message: unexpected(input),
+    }))
+}
+
+// Erreur définitive : aucune autre alternative n'est essayée
+fn fail<'a, T>(input: &'a str, message: impl Into<String>) -> Res<'a, T> {
+    Err(nom::Err::Failure(ParseError {
+        input,
+        message: message.into(),
+    }))
+}
+
+fn unexpected(input: &str) -> String {
+    match input.chars().next() {
+        None => "unexpected end of file".to_owned(),
+        Some(c) => format!("unexpected \"{c}\""),
+    }
+}
 
-use super::ServerBlock;
+/*------------------------------------------------------------*/
+/*-------------------------[ Lexique ]------------------------*/
+/*------------------------------------------------------------*/
 
-// Définition des symboles autorisés dans les identifiants
-#[allow(unused)]
-fn symbols(input: &str) -> IResult<&str, &str> {
-    alt((tag("/"), tag("."), tag("-"), tag("_")))(input)
+// Commentaire : de '#' jusqu'à la fin de la ligne
+fn comment(input: &str) -> Res<'_, &str> {
+    recognize(pair(char('#'), not_line_ending))(input)
 }
 
-// Définition des symboles autorisés dans les identifiants
-fn modifier(input: &str) -> IResult<&str, &str> {
-    alt((tag("="), tag("~")))(input)
+// Espaces blancs et commentaires optionnels
+fn whitespace(input: &str) -> Res<'_, ()> {
+    value((), many0(alt((multispace1, comment))))(input)
 }
 
-// Identifiant : chaîne alphanumérique avec symboles autorisés
-fn identifier(input: &str) -> IResult<&str, &str> {
-    take_while(|c: char| c.is_ascii_whitespace() == false && c != ';')(input)
+// Mot : tout sauf les espaces, ';', '{' et '}' (les '{' et '}' de "${...}" en font partie)
+fn word(input: &str) -> Res<'_, String> {
+    let mut chars = input.char_indices().peekable();
+    let mut in_variable = false;
+    let mut end = input.len();
+
+    if let Some((_, '"' | '\'' | '#')) = chars.peek() {
+        return reject(input);
+    }
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '$' if chars.peek().map(|(_, next)| *next) == Some('{') => {
+                in_variable = true;
+                chars.next();
+            }
+            '}' if in_variable => in_variable = false,
+            c if c.is_whitespace() || c == ';' || c == '{' || c == '}' => {
+                end = index;
+                break;
+            }
+            _ => (),
+        }
+    }
+
+    if end == 0 {
+        return reject(input);
+    }
+
+    Ok((&input[end..], input[..end].to_owned()))
 }
 
-// Espaces blancs optionnels
-fn whitespace(input: &str) -> IResult<&str, &str> {
-    multispace0(input)
+// Chaîne entre guillemets simples ou doubles, avec les échappements \" \' \\ \n \t
+fn quoted(input: &str) -> Res<'_, String> {
+    let quote = match input.chars().next() {
+        Some(quote @ ('"' | '\'')) => quote,
+        _ => return reject(input),
+    };
+
+    let mut string = String::new();
+    let mut chars = input.char_indices().skip(1);
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, 'n')) => string.push('\n'),
+                Some((_, 't')) => string.push('\t'),
+                Some((_, c @ ('"' | '\'' | '\\'))) => string.push(c),
+                Some((_, c)) => {
+                    string.push('\\');
+                    string.push(c);
+                }
+                None => break,
+            },
+            c if c == quote => return Ok((&input[index + 1..], string)),
+            c => string.push(c),
+        }
+    }
+
+    fail(input, "unterminated string")
 }
 
-// Espaces blancs optionnels
-fn space(input: &str) -> IResult<&str, &str> {
-    space1(input)
+fn argument(input: &str) -> Res<'_, String> {
+    alt((quoted, word))(input)
 }
 
-fn has_values(identifiant: &str) -> bool {
-    return identifiant != "internal";
+/*------------------------------------------------------------*/
+/*-----------------------[ Grammaire ]------------------------*/
+/*------------------------------------------------------------*/
+
+// Instruction : directive "nom valeurs;" ou bloc "nom arguments { instructions }",
+// les commentaires sont gardés pour "webserv fmt"
+#[derive(Debug)]
+pub(super) enum Statement<'a> {
+    Directive {
+        name: String,
+        values: Vec<String>,
+        at: &'a str,
+    },
+    Block {
+        name: String,
+        args: Vec<String>,
+        body: Vec<Statement<'a>>,
+        at: &'a str,
+    },
+    // inline : sur la même ligne que l'instruction (ou l'accolade) qui précède
+    Comment {
+        text: String,
+        inline: bool,
+    },
 }
 
-// Directive avec un identifiant et des espaces blancs entre l'identifiant et les valeurs
-fn directive(mut input: &str) -> IResult<&str, (String, Vec<String>)> {
-    input = skip_whitespaces(input);
-    let (mut input, id) = identifier(input)?;
+fn statement(input: &str) -> Res<'_, Statement<'_>> {
+    let (input, space) = multispace0(input)?;
+    if input.starts_with('#') {
+        let (input, text) = comment(input)?;
+        let inline = !space.contains('\n');
+        return Ok((input, Statement::Comment { text: text.to_owned(), inline }));
+    }
+    let at = input;
+    let (mut input, name) = word(input)?;
     let mut values = Vec::new();
-    if has_values(id) {
-        (input, values) = many0(preceded(space1, identifier))(input)?;
+
+    loop {
+        (input, _) = whitespace(input)?;
+        match argument(input) {
+            Ok((rest, value)) => {
+                values.push(value);
+                input = rest;
+            }
+            Err(nom::Err::Error(_)) => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    match input.chars().next() {
+        Some(';') => Ok((&input[1..], Statement::Directive { name, values, at })),
+        Some('{') => {
+            let (input, body) = statements(&input[1..])?;
+            let (input, _) = whitespace(input)?;
+            match input.chars().next() {
+                Some('}') => Ok((
+                    &input[1..],
+                    Statement::Block {
+                        name,
+                        args: values,
+                        body,
+                        at,
+                    },
+                )),
+                Some(_) => fail(input, unexpected(input)),
+                None => fail(input, "unexpected end of file, expecting \"}\""),
+            }
+        }
+        Some(_) => fail(input, unexpected(input)),
+        None => fail(input, "unexpected end of file, expecting \";\" or \"{\""),
     }
-    input = skip_spaces(input);
-    (input, _) = char(';')(input)?;
-    Ok((
-        input,
-        (
-            id.to_owned(),
-            values
-                .iter()
-                .filter(|str| str.is_empty() == false)
-                .map(|str| str.to_string())
-                .collect(),
-        ),
-    ))
 }
 
-fn skip_whitespaces(input: &str) -> &str {
-    match whitespace(input) {
-        Ok((input, _)) => input,
-        Err(_) => input,
+fn statements(input: &str) -> Res<'_, Vec<Statement<'_>>> {
+    many0(statement)(input)
+}
+
+// Arbre syntaxique du fichier entier, sans interprétation des directives
+pub(super) fn syntax_tree(input: &str) -> Res<'_, Vec<Statement<'_>>> {
+    let (input, statements) = statements(input)?;
+    let (input, _) = whitespace(input)?;
+
+    if !input.is_empty() {
+        return fail(input, unexpected(input));
     }
+
+    Ok((input, statements))
 }
 
-fn skip_spaces(input: &str) -> &str {
-    match space(input) {
-        Ok((input, _)) => input,
-        Err(_) => input,
+// Motif d'un "include" (le texte entre "include" et ";"), avec les règles des
+// valeurs : guillemets et échappements
+pub(super) fn include_pattern(input: &str) -> Result<String, String> {
+    let expected = "invalid field: include: expected \"include pattern;\"";
+
+    let (rest, pattern) = match whitespace(input).and_then(|(input, _)| argument(input)) {
+        Ok(parsed) => parsed,
+        Err(nom::Err::Failure(err)) => return Err(format!("invalid field: include: {}", err.message)),
+        Err(_) => return Err(expected.to_owned()),
+    };
+
+    match whitespace(rest) {
+        Ok(("", _)) => Ok(pattern),
+        _ => Err(expected.to_owned()),
     }
 }
 
-// Bloc de type "server" ou "location"
-fn block(input: &str) -> IResult<&str, ServerBlock> {
-    let mut directives: HashMap<String, Vec<String>> = HashMap::new();
-    let mut locations: HashMap<String, LocationBlock> = HashMap::new();
-    let mut cgi: HashMap<String, PathBuf> = HashMap::new();
-    let input = skip_whitespaces(input);
-    let (input, _) = tag("server")(input)?;
-    let input = skip_whitespaces(input);
-    let (input, _) = char('{')(input)?;
-    let mut input = skip_whitespaces(input);
-
-    let mut found = true;
-    while found == true {
-        found = false;
-        input = match directive(input) {
-            Ok((new_input, directive)) => {
-                if directive.0 == "cgi" {
-                    if directive.1.len() != 2 {
-                        return Err(nom::Err::Error(nom::error::Error::new(
-                            input,
-                            nom::error::ErrorKind::Fail,
-                        )));
+// Vrai si la valeur se relit telle quelle sans guillemets
+pub(super) fn is_word(value: &str) -> bool {
+    matches!(word(value), Ok(("", _)))
+}
+
+/*------------------------------------------------------------*/
+/*------------------------[ Variables ]-----------------------*/
+/*------------------------------------------------------------*/
+
+// Variables de configuration : les "-D nom=valeur" de la ligne de commande, puis
+// les directives "set" du bloc et de ses parents, puis l'environnement
+#[derive(Clone)]
+struct Variables<'d> {
+    defines: &'d HashMap<String, String>,
+    set: HashMap<String, String>,
+}
+
+impl Variables<'_> {
+    fn defined(&self, name: &str) -> Option<String> {
+        self.defines.get(name).or(self.set.get(name)).cloned()
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.defined(name).or_else(|| env::var(name).ok())
+    }
+
+    // "set $nom valeur;", la valeur peut utiliser les variables déjà définies
+    fn set<'a>(&mut self, values: Vec<String>, at: &'a str) -> Result<(), ParseError<'a>> {
+        let name = match values.as_slice() {
+            [name, _] => name.strip_prefix('$').filter(|name| is_variable_name(name)),
+            _ => None,
+        };
+        let name = match name {
+            Some(name) => name.to_owned(),
+            None => return error(at, "invalid field: set: expected \"set $name value;\""),
+        };
+
+        let value = self.expand(&values[1], at)?;
+        self.set.insert(name, value);
+        Ok(())
+    }
+
+    fn expand_all<'a>(&self, values: Vec<String>, at: &'a str) -> Result<Vec<String>, ParseError<'a>> {
+        values.into_iter().map(|value| self.expand(&value, at)).collect()
+    }
+
+    // "${NOM}" et "${NOM:-défaut}" doivent être définies (ou avoir une valeur par défaut),
+    // "$nom" n'est remplacée que si "set" ou "-D" la définit : sinon c'est une variable
+    // de requête ($request_uri...), évaluée plus tard
+    fn expand<'a>(&self, value: &str, at: &'a str) -> Result<String, ParseError<'a>> {
+        let mut expanded = String::with_capacity(value.len());
+        let mut rest = value;
+
+        while let Some(dollar) = rest.find('$') {
+            expanded.push_str(&rest[..dollar]);
+            rest = &rest[dollar + 1..];
+
+            if let Some(braced) = rest.strip_prefix('{') {
+                let end = match braced.find('}') {
+                    Some(end) => end,
+                    None => return error(at, format!("unterminated variable in \"{value}\"")),
+                };
+                let (name, default) = match braced[..end].split_once(":-") {
+                    Some((name, default)) => (name, Some(default)),
+                    None => (&braced[..end], None),
+                };
+
+                if !is_variable_name(name) {
+                    return error(at, format!("invalid variable name \"{name}\""));
+                }
+                match self.get(name).or(default.map(str::to_owned)) {
+                    Some(value) => expanded.push_str(&value),
+                    None => return error(at, format!("undefined variable \"{name}\"")),
+                }
+                rest = &braced[end + 1..];
+            } else {
+                let len = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                match self.defined(&rest[..len]) {
+                    Some(value) => expanded.push_str(&value),
+                    None => {
+                        expanded.push('$');
+                        expanded.push_str(&rest[..len]);
                     }
+                }
+                rest = &rest[len..];
+            }
+        }
+
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/*------------------------------------------------------------*/
+/*------------------------[ Contextes ]-----------------------*/
+/*------------------------------------------------------------*/
+
+fn error<'a, T>(at: &'a str, message: impl Into<String>) -> Result<T, ParseError<'a>> {
+    Err(ParseError {
+        input: at,
+        message: message.into(),
+    })
+}
+
+// Directive inconnue, hors de son contexte, au mauvais nombre de valeurs ou aux
+// valeurs invalides : l'erreur est signalée à la position de l'instruction
+fn check_directive<'a>(
+    context: Context,
+    name: &str,
+    values: &[String],
+    regex_location: bool,
+    at: &'a str,
+) -> Result<&'static Directive, ParseError<'a>> {
+    match directives::validate(context, name, values.to_vec(), regex_location) {
+        Ok(directive) => Ok(directive),
+        Err(message) => error(at, message),
+    }
+}
+
+// Directive vérifiée puis ajoutée au bloc : répétée, les valeurs des directives
+// cumulatives (server_name, allowed_methods, error_page) s'ajoutent, les autres
+// ne peuvent apparaître qu'une fois par bloc
+fn insert_directive<'a>(
+    directives: &mut HashMap<String, Vec<String>>,
+    context: Context,
+    name: String,
+    values: Vec<String>,
+    regex_location: bool,
+    at: &'a str,
+) -> Result<(), ParseError<'a>> {
+    let directive = check_directive(context, &name, &values, regex_location, at)?;
+
+    match directives.get_mut(&name) {
+        Some(previous) if directive.is_repeatable() => previous.extend(values),
+        Some(_) => return error(at, format!("\"{name}\" directive is duplicate")),
+        None => {
+            directives.insert(name, values);
+        }
+    }
 
-                    let extension = if directive.1[0].starts_with(".") {
-                        directive.1[0][1..].to_string()
-                    } else {
-                        return Err(nom::Err::Error(nom::error::Error::new(
-                            input,
-                            nom::error::ErrorKind::Fail,
-                        )));
-                    };
+    Ok(())
+}
 
-                    cgi.insert(extension, PathBuf::from(directive.1[1].as_str()));
+// "cgi .extension path;" dans un bloc http, server ou location, le point est facultatif
+fn cgi<'a>(values: &[String], at: &'a str) -> Result<(String, PathBuf), ParseError<'a>> {
+    let extension = match values {
+        [extension, _] => extension.strip_prefix('.').unwrap_or(extension),
+        _ => return error(at, "invalid field: cgi: expected \"cgi .extension path;\""),
+    };
+    if extension.is_empty() {
+        return error(at, "invalid field: cgi: empty extension");
+    }
+    Ok((extension.to_string(), PathBuf::from(values[1].as_str())))
+}
+
+// Modificateurs de location
+fn is_modifier(value: &str) -> bool {
+    matches!(value, "=" | "~" | "~*" | "^~")
+}
+
+// Bloc de type "server" : des directives et des locations
+fn block<'a>(body: Vec<Statement<'a>>, vars: &Variables) -> Result<ServerBlock, ParseError<'a>> {
+    let mut vars = vars.clone();
+    let mut directives: HashMap<String, Vec<String>> = HashMap::new();
+    let mut locations: Vec<LocationBlock> = Vec::new();
+    let mut cgi_map: HashMap<String, PathBuf> = HashMap::new();
+
+    for statement in body {
+        match statement {
+            Statement::Comment { .. } => (),
+            Statement::Directive { name, values, at } if name == "set" => vars.set(values, at)?,
+            Statement::Directive { name, values, at } => {
+                let values = vars.expand_all(values, at)?;
+                if name == "cgi" {
+                    let (extension, path) = cgi(&values, at)?;
+                    cgi_map.insert(extension, path);
                 } else {
-                    directives.insert(directive.0, directive.1);
+                    insert_directive(&mut directives, Context::Server, name, values, false, at)?;
                 }
-                found = true;
-                new_input
             }
-            Err(_) => input,
-        };
-        input = match location_block(input) {
-            Ok((new_input, location)) => {
-                if locations.contains_key(location.path.as_str()) {
-                    return Err(nom::Err::Error(nom::error::Error::new(
-                        input,
-                        nom::error::ErrorKind::Fail,
-                    )));
+            Statement::Block { name, args, body, at } if name == "location" => {
+                let location = location_block(args, body, at, &vars)?;
+                add_location(&mut locations, location, at)?;
+            }
+            Statement::Block { name, at, .. } => {
+                return error(at, format!("\"{name}\" block is not allowed in server"))
+            }
+        }
+    }
+
+    Ok(ServerBlock {
+        locations,
+        directives,
+        cgi: cgi_map,
+    })
+}
+
+// Bloc "http" : des blocs server et les directives qui leur servent de valeurs par défaut
+fn http_block<'a>(body: Vec<Statement<'a>>, vars: &Variables) -> Result<Vec<ServerBlock>, ParseError<'a>> {
+    let mut vars = vars.clone();
+    let mut defaults = ServerBlock::default();
+    let mut servers: Vec<ServerBlock> = Vec::new();
+
+    for statement in body {
+        match statement {
+            Statement::Comment { .. } => (),
+            Statement::Directive { name, values, at } if name == "set" => vars.set(values, at)?,
+            Statement::Directive { name, values, at } => {
+                let values = vars.expand_all(values, at)?;
+                if name == "cgi" {
+                    let (extension, path) = cgi(&values, at)?;
+                    defaults.cgi.insert(extension, path);
+                } else {
+                    insert_directive(&mut defaults.directives, Context::Http, name, values, false, at)?;
                 }
-                locations.insert(location.path.clone(), location);
-                found = true;
-                new_input
             }
-            Err(_) => input,
-        };
+            Statement::Block { name, args, body, at } if name == "server" => {
+                if !args.is_empty() {
+                    return error(at, "invalid server: \"server\" takes no arguments");
+                }
+                servers.push(block(body, &vars)?);
+            }
+            Statement::Block { name, at, .. } => {
+                return error(at, format!("\"{name}\" block is not allowed in http"))
+            }
+        }
     }
-    input = skip_whitespaces(input);
-    let (input, _) = char('}')(input)?;
-    Ok((
-        input,
-        ServerBlock {
-            locations: locations,
-            directives: directives,
-            cgi: cgi,
-        },
-    ))
-}
-
-// Bloc de type "location" : Cette fonction retourne une directive avec un identifiant et une liste de valeurs
-fn location_block(mut input: &str) -> IResult<&str, LocationBlock> {
-    input = skip_whitespaces(input);
-    (input, _) = tag("location")(input)?;
-    input = skip_spaces(input);
-    let (mut input, modifier) = match modifier(input) {
-        Ok((input, modifier)) => (input, Some(modifier.to_owned())),
-        _ => (input, None),
+
+    // les valeurs du server l'emportent, les directives absentes sont héritées
+    for server in servers.iter_mut() {
+        for (name, values) in &defaults.directives {
+            if !server.directives.contains_key(name) {
+                server.directives.insert(name.clone(), values.clone());
+            }
+        }
+        if server.cgi.is_empty() {
+            server.cgi = defaults.cgi.clone();
+        }
+    }
+
+    Ok(servers)
+}
+
+// Bloc de type "location" : des directives et des locations imbriquées
+fn location_block<'a>(
+    args: Vec<String>,
+    body: Vec<Statement<'a>>,
+    at: &'a str,
+    vars: &Variables,
+) -> Result<LocationBlock, ParseError<'a>> {
+    let mut vars = vars.clone();
+    let args = vars.expand_all(args, at)?;
+    let (modifier, path) = match args.as_slice() {
+        [path] if !is_modifier(path) => (None, path.clone()),
+        [modifier, path] if is_modifier(modifier) => (Some(modifier.clone()), path.clone()),
+        [modifier, _] => return error(at, format!("invalid location modifier: {modifier}")),
+        _ => return error(at, "invalid location: expected \"location [modifier] path\""),
     };
-    input = skip_spaces(input);
-    let (mut input, path) = identifier(input)?;
-    input = skip_whitespaces(input);
-    (input, _) = char('{')(input)?;
-    let (mut input, directives) = many0(directive)(input)?;
-    input = skip_whitespaces(input);
-    (input, _) = char('}')(input)?;
+    let regex = matches!(modifier.as_deref(), Some("~" | "~*"));
 
     let mut infos: HashMap<String, Vec<String>> = HashMap::new();
-    let mut cgi: HashMap<String, PathBuf> = HashMap::new();
-
-    for directive in directives {
-        if directive.0 == "cgi" {
-            if directive.1.len() != 2 {
-                return Err(nom::Err::Error(nom::error::Error::new(
-                    input,
-                    nom::error::ErrorKind::Fail,
-                )));
-            }
-            let extension = if directive.1[0].starts_with(".") {
-                directive.1[0][1..].to_string()
-            } else {
-                directive.1[0].to_string()
-            };
-            cgi.insert(extension, PathBuf::from(directive.1[1].as_str()));
-        } else {
-            infos.insert(directive.0, directive.1);
+    let mut cgi_map: HashMap<String, PathBuf> = HashMap::new();
+    let mut locations: Vec<LocationBlock> = Vec::new();
+
+    for statement in body {
+        match statement {
+            Statement::Comment { .. } => (),
+            Statement::Directive { name, values, at } if name == "set" => vars.set(values, at)?,
+            Statement::Directive { name, values, at } => {
+                let values = vars.expand_all(values, at)?;
+                if name == "cgi" {
+                    let (extension, path) = cgi(&values, at)?;
+                    cgi_map.insert(extension, path);
+                } else {
+                    insert_directive(&mut infos, Context::Location, name, values, regex, at)?;
+                }
+            }
+            Statement::Block { name, args, body, at } if name == "location" => {
+                let location = location_block(args, body, at, &vars)?;
+                add_location(&mut locations, location, at)?;
+            }
+            Statement::Block { name, at, .. } => {
+                return error(at, format!("\"{name}\" block is not allowed in location"))
+            }
         }
     }
 
-    input = skip_whitespaces(input);
-    // Crée un bloc de type "location" similaire à une directive
-    Ok((
-        input,
-        LocationBlock {
-            modifier: modifier,
-            path: path.to_owned(),
-            directives: infos,
-            cgi: cgi,
-        },
-    ))
-}
-
-// Fichier de configuration : commence avec SOI (Start of Input) et finit avec EOI (End of Input)
-pub fn config(input: &str) -> IResult<&str, Vec<ServerBlock>> {
-    let (input, servs) = many0(block)(input)?;
-
-    let input = skip_whitespaces(input);
-    if input.is_empty() == false {
-        Err(nom::Err::Error(nom::error::Error::new(
-            "File not entirely readed",
-            nom::error::ErrorKind::Fail,
-        )))
-    } else {
-        Ok((input, servs))
+    Ok(LocationBlock {
+        modifier,
+        path,
+        directives: infos,
+        cgi: cgi_map,
+        locations,
+    })
+}
+
+fn add_location<'a>(
+    locations: &mut Vec<LocationBlock>,
+    location: LocationBlock,
+    at: &'a str,
+) -> Result<(), ParseError<'a>> {
+    if locations
+        .iter()
+        .any(|other| other.path == location.path && other.modifier == location.modifier)
+    {
+        return error(at, format!("duplicate location \"{}\"", location.path));
     }
+
+    locations.push(location);
+    Ok(())
+}
+
+// Fichier de configuration : des directives du contexte principal (ex: worker_connections),
+// un bloc "http" et des blocs "server", jusqu'à la fin de l'entrée
+pub fn config<'a>(input: &'a str, defines: &HashMap<String, String>) -> Res<'a, MainBlock> {
+    let (input, statements) = syntax_tree(input)?;
+
+    match main_block(statements, defines) {
+        Ok(main) => Ok((input, main)),
+        Err(err) => Err(nom::Err::Failure(err)),
+    }
+}
+
+// Contexte principal, quelle que soit la syntaxe d'origine (texte, TOML, JSON ou YAML)
+pub(super) fn main_block<'a>(
+    statements: Vec<Statement<'a>>,
+    defines: &HashMap<String, String>,
+) -> Result<MainBlock, ParseError<'a>> {
+    let mut main = MainBlock::default();
+    let mut http = false;
+    let mut vars = Variables {
+        defines,
+        set: HashMap::new(),
+    };
+
+    for statement in statements {
+        match statement {
+            Statement::Comment { .. } => (),
+            Statement::Directive { name, values, at } if name == "set" => vars.set(values, at)?,
+            Statement::Directive { name, values, at } => {
+                let values = vars.expand_all(values, at)?;
+                insert_directive(&mut main.directives, Context::Main, name, values, false, at)?;
+            }
+            Statement::Block { name, args, body, at } if name == "server" => {
+                if !args.is_empty() {
+                    return error(at, "invalid server: \"server\" takes no arguments");
+                }
+                main.servers.push(block(body, &vars)?);
+            }
+            Statement::Block { name, args, body, at } if name == "http" => {
+                if !args.is_empty() {
+                    return error(at, "invalid http: \"http\" takes no arguments");
+                }
+                if http {
+                    return error(at, "duplicate \"http\" block");
+                }
+                http = true;
+                main.servers.extend(http_block(body, &vars)?);
+            }
+            Statement::Block { name, at, .. } => {
+                return error(at, format!("\"{name}\" block is not allowed here"));
+            }
+        }
+    }
+
+    Ok(main)
 }
@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    path::PathBuf,
+};
+
+use super::{
+    config_parsing::{is_word, Statement},
+    LocationBlock, MainBlock, ServerBlock,
+};
+
+/// `webserv fmt`: the syntax tree printed back. Statements keep their order
+/// (`set` and regex locations depend on it) and their comments, blocks are
+/// indented by 4 spaces and separated from their neighbours by a blank line.
+pub fn format(statements: &[Statement]) -> String {
+    let mut out = String::new();
+    let _ = write_statements(&mut out, 0, statements);
+    out
+}
+
+fn write_statements(out: &mut String, depth: usize, statements: &[Statement]) -> fmt::Result {
+    let mut previous_block = None;
+
+    for (index, statement) in statements.iter().enumerate() {
+        match statement {
+            // appended to the line of the statement or the brace before it
+            Statement::Comment { text, inline: true } if out.ends_with('\n') => {
+                out.pop();
+                writeln!(out, " {text}")?;
+                continue;
+            }
+            _ => (),
+        }
+
+        // own line comments stay with the statement that follows them
+        if index == 0 || !is_own_line_comment(&statements[index - 1]) {
+            let is_block = statements[index..]
+                .iter()
+                .find(|statement| !is_own_line_comment(statement))
+                .is_some_and(|statement| matches!(statement, Statement::Block { .. }));
+            if previous_block.is_some_and(|previous| previous || is_block) {
+                out.push('\n');
+            }
+            previous_block = Some(is_block);
+        }
+
+        indent(out, depth)?;
+        match statement {
+            Statement::Comment { text, .. } => writeln!(out, "{text}")?,
+            Statement::Directive { name, values, .. } => {
+                out.push_str(name);
+                for value in values {
+                    write!(out, " {}", quote(value))?;
+                }
+                out.push_str(";\n");
+            }
+            Statement::Block { name, args, body, .. } => {
+                out.push_str(name);
+                for arg in args {
+                    write!(out, " {}", quote(arg))?;
+                }
+                out.push_str(" {\n");
+                write_statements(out, depth + 1, body)?;
+                indent(out, depth)?;
+                out.push_str("}\n");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn is_own_line_comment(statement: &Statement) -> bool {
+    matches!(statement, Statement::Comment { inline: false, .. })
+}
+
+/// Configuration text of the parsed blocks: includes are already expanded,
+/// variables substituted and `http` defaults merged into their servers.
+/// Directives are sorted by name, locations keep their file order.
+impl fmt::Display for MainBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_directives(f, 0, &self.directives)?;
+
+        for (index, server) in self.servers.iter().enumerate() {
+            if index > 0 || !self.directives.is_empty() {
+                f.write_char('\n')?;
+            }
+            write!(f, "{server}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for ServerBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("server {\n")?;
+        write_body(f, 1, &self.directives, &self.cgi, &self.locations)?;
+        f.write_str("}\n")
+    }
+}
+
+impl fmt::Display for LocationBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_location(f, 0, self)
+    }
+}
+
+fn write_location(f: &mut impl Write, depth: usize, location: &LocationBlock) -> fmt::Result {
+    indent(f, depth)?;
+    f.write_str("location ")?;
+    if let Some(modifier) = &location.modifier {
+        write!(f, "{modifier} ")?;
+    }
+    writeln!(f, "{} {{", quote(&location.path))?;
+    write_body(f, depth + 1, &location.directives, &location.cgi, &location.locations)?;
+    indent(f, depth)?;
+    f.write_str("}\n")
+}
+
+fn write_body(
+    f: &mut impl Write,
+    depth: usize,
+    directives: &HashMap<String, Vec<String>>,
+    cgi: &HashMap<String, PathBuf>,
+    locations: &[LocationBlock],
+) -> fmt::Result {
+    write_directives(f, depth, directives)?;
+
+    let mut cgi: Vec<_> = cgi.iter().collect();
+    cgi.sort();
+    for (extension, path) in cgi {
+        indent(f, depth)?;
+        writeln!(f, "cgi {} {};", quote(&format!(".{extension}")), quote(&path.to_string_lossy()))?;
+    }
+
+    for location in locations {
+        f.write_char('\n')?;
+        write_location(f, depth, location)?;
+    }
+
+    Ok(())
+}
+
+fn write_directives(
+    f: &mut impl Write,
+    depth: usize,
+    directives: &HashMap<String, Vec<String>>,
+) -> fmt::Result {
+    let mut directives: Vec<_> = directives.iter().collect();
+    directives.sort();
+
+    for (name, values) in directives {
+        indent(f, depth)?;
+        f.write_str(name)?;
+        for value in values {
+            write!(f, " {}", quote(value))?;
+        }
+        f.write_str(";\n")?;
+    }
+
+    Ok(())
+}
+
+fn indent(f: &mut impl Write, depth: usize) -> fmt::Result {
+    for _ in 0..depth {
+        f.write_str("    ")?;
+    }
+    Ok(())
+}
+
+/// `value` as the parser reads it back: quoted unless it is a single word
+pub fn quote(value: &str) -> String {
+    if is_word(value) {
+        return value.to_owned();
+    }
+
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::super::config_parsing::{config, syntax_tree};
+    use super::*;
+
+    const SAMPLE: &str = r#"
+# comment
+worker_connections 64; # inline
+set $base /srv;
+http {
+    root ${base}/www;
+    error_page 404 /404.html;
+    error_page 500 =200 /fallback.html;
+    cgi .py /usr/bin/python3;
+    server {
+        listen 8080 default;
+        server_name a.fr;
+        server_name "b.fr";
+        return 200 "base=$base uri=$request_uri; done";
+        location ~* "\.(png|jpe?g)$" { root '/srv/images dir'; }
+        location /api/ {
+            allowed_methods GET POST;
+            location = /api/health { internal; }
+        }
+    }
+}
+server { listen 8081; root "a\"b\\c"; charset ""; }
+"#;
+
+    fn parse(text: &str) -> MainBlock {
+        config(text, &HashMap::new()).unwrap().1
+    }
+
+    fn fmt(text: &str) -> String {
+        format(&syntax_tree(text).unwrap().1)
+    }
+
+    #[test]
+    fn fmt_round_trip_is_lossless() {
+        for text in [SAMPLE, include_str!("../../default.conf")] {
+            let formatted = fmt(text);
+
+            assert_eq!(parse(&formatted), parse(text));
+            assert_eq!(fmt(&formatted), formatted);
+        }
+    }
+
+    #[test]
+    fn fmt_keeps_comments() {
+        let formatted = fmt(SAMPLE);
+
+        assert!(formatted.starts_with("# comment\nworker_connections 64; # inline\n"));
+    }
+
+    #[test]
+    fn dump_round_trip_is_lossless() {
+        for text in [SAMPLE, include_str!("../../default.conf")] {
+            let main = parse(text);
+
+            assert_eq!(parse(&main.to_string()), main);
+        }
+    }
+
+    #[test]
+    fn repeated_directives_add_up() {
+        let main = parse(SAMPLE);
+
+        assert_eq!(main.servers[0].directives["server_name"], ["a.fr", "b.fr"]);
+        assert_eq!(main.servers[0].directives["error_page"].len(), 5);
+    }
+}
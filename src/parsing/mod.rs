@@ -1,42 +1,95 @@
 mod config_parsing;
+mod dump;
+mod source;
+mod structured;
 
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-use config_parsing::config;
-use tokio::{fs::File, io::AsyncReadExt as _};
+use colored::Colorize;
+use config_parsing::{config, syntax_tree, ParseError};
+use source::Source;
+use structured::Format;
 
-#[derive(Debug, Clone)]
+pub use dump::quote;
+pub use structured::schema;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct LocationBlock {
     pub modifier: Option<String>,
     pub path: String,
     pub directives: HashMap<String, Vec<String>>,
     pub cgi: HashMap<String, PathBuf>,
+    /// nested locations, in file order
+    pub locations: Vec<LocationBlock>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct ServerBlock {
     pub directives: HashMap<String, Vec<String>>,
-    pub locations: HashMap<String, LocationBlock>,
+    /// in file order: regex locations are tried in that order
+    pub locations: Vec<LocationBlock>,
     pub cgi: HashMap<String, PathBuf>,
 }
 
-pub async fn get_config(path: String) -> Result<Vec<ServerBlock>, String> {
-    let mut file = match File::open(path.as_str()).await {
-        Ok(file) => file,
-        Err(err) => return Err(format!("{path}: {err}")),
-    };
-    let mut content = String::new();
-    match file.read_to_string(&mut content).await {
-        Ok(_) => (),
-        Err(err) => return Err(format!("failed to read {path}: {err}")),
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MainBlock {
+    pub directives: HashMap<String, Vec<String>>,
+    pub servers: Vec<ServerBlock>,
+}
+
+/// Reads `path` with its `include`s expanded, relative to the including file,
+/// or as TOML, JSON or YAML after its extension (see `structured::load`).
+/// `defines` (`-D name=value`) take precedence over `set` and the environment.
+pub async fn get_config(path: String, defines: &HashMap<String, String>) -> Result<MainBlock, String> {
+    if let Some(format) = Format::of(Path::new(&path)) {
+        return structured::load(Path::new(&path), format, defines);
     }
 
-    let (_, servers) = match config(content.as_str()) {
-        Ok(config) => config,
-        Err(err) => return Err(format!("Bad config file: {err}")),
+    let source = Source::load(Path::new(&path))?;
+    for warning in source.warnings() {
+        eprintln!("{}", format!("Warning: {warning}").yellow());
+    }
+
+    match config(source.text(), defines) {
+        Ok((_, main)) => Ok(main),
+        Err(err) => Err(bad_config(&source, err)),
+    }
+}
+
+/// `webserv fmt`: the text of `path` reformatted, see `dump::format`.
+/// Its `include`s are kept as directives, or replaced by the included files with `flatten`.
+pub fn format_config(path: &str, flatten: bool) -> Result<String, String> {
+    if Format::of(Path::new(path)).is_some() {
+        return Err(format!("{path}: only the nginx-style syntax can be formatted"));
+    }
+
+    let source = match flatten {
+        true => Source::load(Path::new(path))?,
+        false => Source::read(Path::new(path))?,
     };
 
-    Ok(servers)
+    match syntax_tree(source.text()) {
+        Ok((_, statements)) => Ok(dump::format(&statements)),
+        Err(err) => Err(bad_config(&source, err)),
+    }
+}
+
+fn bad_config(source: &Source, err: nom::Err<ParseError>) -> String {
+    match err {
+        nom::Err::Error(err) | nom::Err::Failure(err) => {
+            let offset = source.text().len() - err.input.len();
+            format!(
+                "Bad config file: {}: {}\n{}",
+                source.position(offset),
+                err.message,
+                source.snippet(offset)
+            )
+        }
+        err => format!("Bad config file: {err}"),
+    }
 }
 
 #[allow(unused)]
@@ -52,12 +105,9 @@ impl ServerBlock {
     }
 
     pub fn get_location(&self, path: String) -> Option<LocationBlock> {
-        let value = self.locations.get(&path);
-
-        if value.is_some() {
-            Some((*value.unwrap()).clone())
-        } else {
-            None
-        }
+        self.locations
+            .iter()
+            .find(|location| location.path == path)
+            .cloned()
     }
 }
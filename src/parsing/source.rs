@@ -0,0 +1,359 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use super::config_parsing::include_pattern;
+
+/// Configuration text with every `include` expanded in place. Segments
+/// remember where each part of the text comes from, so positions in the
+/// expanded text can be reported as `file:line:column`.
+#[derive(Debug, Default)]
+pub struct Source {
+    text: String,
+    files: Vec<PathBuf>,
+    segments: Vec<Segment>,
+    warnings: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    /// offset of the segment in the expanded text
+    start: usize,
+    /// index in `files`
+    file: usize,
+    /// line of the segment start in its file (1-based)
+    line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file.display(), self.line, self.column)
+    }
+}
+
+impl Source {
+    pub fn load(path: &Path) -> Result<Source, String> {
+        let mut source = Source::default();
+        let mut stack = Vec::new();
+
+        source.expand_file(path, &mut stack)?;
+
+        Ok(source)
+    }
+
+    /// `path` alone, its `include`s left as directives (`webserv fmt`)
+    pub fn read(path: &Path) -> Result<Source, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => return Err(format!("{}: {err}", path.display())),
+        };
+
+        Ok(Source {
+            text,
+            files: vec![path.to_path_buf()],
+            segments: vec![Segment { start: 0, file: 0, line: 1 }],
+            warnings: Vec::new(),
+        })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Includes whose wildcard matched no file
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Position of `offset` (in the expanded text) in its original file
+    pub fn position(&self, offset: usize) -> Position {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.start <= offset)
+            .saturating_sub(1);
+
+        let offset = offset.min(self.text.len());
+        let column = self.text[..offset].chars().rev().take_while(|c| *c != '\n').count() + 1;
+
+        match self.segments.get(index) {
+            Some(segment) => {
+                let lines = self.text[segment.start..offset].matches('\n').count();
+                Position {
+                    file: self.files[segment.file].clone(),
+                    line: segment.line + lines,
+                    column,
+                }
+            }
+            None => Position {
+                file: PathBuf::new(),
+                line: 1,
+                column,
+            },
+        }
+    }
+
+    /// The line containing `offset` and a caret under it
+    pub fn snippet(&self, offset: usize) -> String {
+        let offset = offset.min(self.text.len());
+        let start = self.text[..offset].rfind('\n').map_or(0, |index| index + 1);
+        let end = self.text[offset..].find('\n').map_or(self.text.len(), |index| offset + index);
+        let line = &self.text[start..end];
+
+        // tabs are kept under the caret so that it stays aligned
+        let padding: String = self.text[start..offset]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        format!("{line}\n{padding}^")
+    }
+
+    fn expand_file(&mut self, path: &Path, stack: &mut Vec<PathBuf>) -> Result<(), String> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => return Err(format!("{}: {err}", path.display())),
+        };
+        let canonical = std::fs::canonicalize(path).unwrap_or(path.to_path_buf());
+
+        if stack.contains(&canonical) {
+            let chain: Vec<String> = stack
+                .iter()
+                .chain([&canonical])
+                .map(|file| file.display().to_string())
+                .collect();
+            return Err(format!("include cycle: {}", chain.join(" -> ")));
+        }
+
+        stack.push(canonical);
+        self.files.push(path.to_path_buf());
+        let file = self.files.len() - 1;
+        let mut line = 1;
+        let mut rest = content.as_str();
+
+        self.segments.push(Segment { start: self.text.len(), file, line });
+
+        while let Some((before, pattern, after)) = next_include(rest) {
+            self.text.push_str(before);
+            line += before.matches('\n').count();
+            let position = Position {
+                file: path.to_path_buf(),
+                line,
+                column: before.chars().rev().take_while(|c| *c != '\n').count() + 1,
+            };
+
+            // included files start on their own line, columns stay right
+            self.text.push('\n');
+            let included = resolve_include(path, pattern).map_err(|err| format!("{position}: {err}"))?;
+            if included.is_empty() {
+                self.warnings.push(format!("{position}: include: {} matches no file", pattern.trim()));
+            }
+            for included in included {
+                self.expand_file(&included, stack)
+                    .map_err(|err| format!("{position}: {err}"))?;
+            }
+            self.text.push('\n');
+
+            // the including file goes on after the directive
+            let directive = &rest[before.len()..rest.len() - after.len()];
+            line += directive.matches('\n').count();
+            self.segments.push(Segment { start: self.text.len(), file, line });
+            rest = after;
+        }
+
+        self.text.push_str(rest);
+        stack.pop();
+        Ok(())
+    }
+}
+
+/// Splits `text` around its first `include pattern;` directive:
+/// the text before it, the pattern and the text after it.
+/// Comments and quoted strings are skipped like the parser does.
+fn next_include(text: &str) -> Option<(&str, &str, &str)> {
+    let mut statement_start = true;
+    let mut chars = text.char_indices();
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '{' | '}' | ';' => statement_start = true,
+            c if c.is_whitespace() => (),
+            '#' if is_token_start(text, index) => {
+                chars.by_ref().find(|(_, c)| *c == '\n');
+            }
+            '"' | '\'' if is_token_start(text, index) => {
+                statement_start = false;
+                let mut escaped = false;
+                chars.by_ref().find(|(_, next)| {
+                    let end = !escaped && *next == c;
+                    escaped = !escaped && *next == '\\';
+                    end
+                });
+            }
+            _ if !statement_start => (),
+            _ => {
+                statement_start = false;
+
+                let statement = &text[index..];
+                let args = match statement.strip_prefix("include") {
+                    Some(args) if args.starts_with(char::is_whitespace) => args,
+                    _ => continue,
+                };
+                let end = directive_end(args)?;
+
+                return Some((&text[..index], &args[..end], &args[end + 1..]));
+            }
+        }
+    }
+
+    None
+}
+
+/// Offset of the `;` ending the arguments of a directive, quoted strings skipped
+fn directive_end(args: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (index, c) in args.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(end) if c == end => quote = None,
+            Some(_) => (),
+            None if c == ';' => return Some(index),
+            None if (c == '"' || c == '\'') && is_token_start(args, index) => quote = Some(c),
+            None => (),
+        }
+    }
+
+    None
+}
+
+/// Comments and quotes only start a token, like in `a#b` they are part of a word
+fn is_token_start(text: &str, index: usize) -> bool {
+    match text[..index].chars().next_back() {
+        None => true,
+        Some(c) => c.is_whitespace() || c == ';' || c == '{' || c == '}',
+    }
+}
+
+/// Files designated by `pattern` (the directive arguments, unquoted like any
+/// value), relative to the including file and sorted.
+/// A pattern with wildcards may match nothing, a plain path must exist.
+fn resolve_include(including: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let pattern = include_pattern(pattern)?;
+    if pattern.is_empty() {
+        return Err("invalid field: include: empty pattern".to_owned());
+    }
+
+    let pattern = match Path::new(&pattern).is_absolute() {
+        true => PathBuf::from(pattern),
+        false => including.parent().unwrap_or(Path::new("")).join(pattern),
+    };
+    let pattern = pattern.to_string_lossy();
+
+    let paths = match glob::glob(&pattern) {
+        Ok(paths) => paths,
+        Err(err) => return Err(format!("invalid field: include: {pattern}: {err}")),
+    };
+
+    let mut files = Vec::new();
+    for path in paths {
+        match path {
+            Ok(path) if path.is_file() => files.push(path),
+            Ok(_) => (),
+            Err(err) => return Err(format!("include: {err}")),
+        }
+    }
+
+    let wildcard = pattern.contains(['*', '?', '[']);
+    if files.is_empty() && !wildcard {
+        return Err(format!("include: {pattern}: no such file"));
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// A directory of its own under the system temporary directory
+    fn tree(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("webserv-source-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn quoted_include_patterns_are_unquoted() {
+        let root = tree(
+            "quoted",
+            &[
+                ("main.conf", "include \"conf.d/*.conf\";\ninclude 'with space/b;c.conf';\n"),
+                ("conf.d/a.conf", "worker_connections 8;\n"),
+                ("with space/b;c.conf", "pid b.pid;\n"),
+            ],
+        );
+
+        let source = Source::load(&root.join("main.conf")).unwrap();
+
+        assert!(source.text().contains("worker_connections 8;"));
+        assert!(source.text().contains("pid b.pid;"));
+        assert!(source.warnings().is_empty());
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn empty_wildcard_is_a_warning() {
+        let root = tree("empty", &[("main.conf", "include \"conf.d/*.conf\";\n")]);
+
+        let source = Source::load(&root.join("main.conf")).unwrap();
+
+        assert_eq!(source.warnings().len(), 1);
+        assert!(source.warnings()[0].contains("matches no file"));
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn include_takes_a_single_pattern() {
+        let root = tree("single", &[("main.conf", "include a.conf b.conf;\n")]);
+
+        let err = Source::load(&root.join("main.conf")).unwrap_err();
+
+        assert!(err.contains("expected \"include pattern;\""), "{err}");
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn value_errors_point_to_their_file_and_line() {
+        let root = tree(
+            "values",
+            &[
+                ("main.conf", "include conf.d/*.conf;\n"),
+                ("conf.d/a.conf", "server {\n    listen 8080;\n    keepalive_timeout 1y;\n}\n"),
+            ],
+        );
+        let main = root.join("main.conf").to_string_lossy().into_owned();
+
+        let err = crate::parsing::get_config(main, &Default::default()).await.unwrap_err();
+
+        assert!(err.contains("a.conf:3:5: invalid"), "{err}");
+        let _ = fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,481 @@
+use std::{collections::HashMap, path::Path};
+
+use serde_json::{json, Map, Value};
+
+use crate::server::directives::{self, Arity, Context, Directive, DIRECTIVES};
+
+use super::{
+    config_parsing::{main_block, Statement},
+    MainBlock,
+};
+
+/// Configuration files read through serde instead of the nginx-style parser,
+/// detected by their extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    pub fn of(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+
+    fn parse(&self, text: &str) -> Result<Value, String> {
+        match self {
+            Format::Toml => toml::from_str(text).map_err(|err| err.to_string()),
+            Format::Json => serde_json::from_str(text).map_err(|err| err.to_string()),
+            Format::Yaml => serde_yaml::from_str(text).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+/// A document is converted to the statements the nginx-style syntax gives,
+/// then validated and merged by the same code:
+///
+/// ```json
+/// { "worker_connections": 64,
+///   "http": { "root": "/srv", "servers": [
+///     { "listen": [8080, "default"], "server_name": ["a.fr", "b.fr"],
+///       "error_page": [[404, "/404.html"], [500, "=200", "/500.html"]],
+///       "cgi": { ".py": "/usr/bin/python3" },
+///       "locations": [{ "modifier": "~", "path": "\\.php$", "internal": true }] } ] } }
+/// ```
+///
+/// A list of values gives the values of one directive, a list of lists
+/// repeats it. Errors are reported with the key path (`servers[0].root`).
+pub fn load(path: &Path, format: Format, defines: &HashMap<String, String>) -> Result<MainBlock, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => return Err(format!("{}: {err}", path.display())),
+    };
+
+    document(format, &text, defines).map_err(|err| format!("Bad config file: {}: {err}", path.display()))
+}
+
+fn document(format: Format, text: &str, defines: &HashMap<String, String>) -> Result<MainBlock, String> {
+    let mut paths = String::new();
+    let nodes = block(&format.parse(text)?, "", false, &mut paths)?;
+
+    main_block(statements(nodes, &paths), defines).map_err(|err| {
+        let offset = paths.len() - err.input.len();
+        let key = paths[offset..].lines().next().unwrap_or_default();
+        format!("{key}: {}", err.message)
+    })
+}
+
+/// Statement without its position yet: `path` is an offset in the key paths
+enum Node {
+    Directive {
+        name: String,
+        values: Vec<String>,
+        path: usize,
+    },
+    Block {
+        name: String,
+        args: Vec<String>,
+        body: Vec<Node>,
+        path: usize,
+    },
+}
+
+/// The key paths are written one per line, each statement points to its own
+fn statements(nodes: Vec<Node>, paths: &str) -> Vec<Statement<'_>> {
+    nodes
+        .into_iter()
+        .map(|node| match node {
+            Node::Directive { name, values, path } => Statement::Directive {
+                name,
+                values,
+                at: &paths[path..],
+            },
+            Node::Block { name, args, body, path } => Statement::Block {
+                name,
+                args,
+                body: statements(body, paths),
+                at: &paths[path..],
+            },
+        })
+        .collect()
+}
+
+fn record(paths: &mut String, path: &str) -> usize {
+    let offset = paths.len();
+    paths.push_str(path);
+    paths.push('\n');
+    offset
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    match prefix.is_empty() {
+        true => key.to_owned(),
+        false => format!("{prefix}.{key}"),
+    }
+}
+
+/// Body of the main context, `http`, a server (`servers[i]`) or a location (`locations[i]`)
+fn block(value: &Value, prefix: &str, location: bool, paths: &mut String) -> Result<Vec<Node>, String> {
+    let object = match value {
+        Value::Object(object) => object,
+        _ => return Err(format!("{}: expected a table of directives", display(prefix))),
+    };
+    let mut nodes = Vec::new();
+    // "set" first: the other values may use its variables, whatever the key order
+    let (set, others): (Vec<_>, Vec<_>) = object.iter().partition(|(key, _)| *key == "set");
+
+    for (key, value) in set.into_iter().chain(others) {
+        let path = join(prefix, key);
+        match key.as_str() {
+            "path" | "modifier" if location => (),
+            "http" => nodes.push(Node::Block {
+                name: key.to_owned(),
+                args: Vec::new(),
+                body: block(value, &path, false, paths)?,
+                path: record(paths, &path),
+            }),
+            "servers" | "locations" => {
+                let location = key == "locations";
+                for (index, value) in list(value, &path)?.iter().enumerate() {
+                    let path = format!("{path}[{index}]");
+                    nodes.push(Node::Block {
+                        name: key.trim_end_matches('s').to_owned(),
+                        args: match location {
+                            true => location_args(value, &path)?,
+                            false => Vec::new(),
+                        },
+                        body: block(value, &path, location, paths)?,
+                        path: record(paths, &path),
+                    });
+                }
+            }
+            "cgi" => {
+                let object = match value {
+                    Value::Object(object) => object,
+                    _ => return Err(format!("{path}: expected a table of \".extension\" = \"path\"")),
+                };
+                for (extension, interpreter) in object {
+                    nodes.push(Node::Directive {
+                        name: key.to_owned(),
+                        values: vec![extension.to_owned(), scalar(interpreter, &path, key)?],
+                        path: record(paths, &path),
+                    });
+                }
+            }
+            _ => {
+                for values in directive_values(value, &path, key)? {
+                    nodes.push(Node::Directive {
+                        name: key.to_owned(),
+                        values,
+                        path: record(paths, &path),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(nodes)
+}
+
+fn location_args(value: &Value, path: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+
+    if let Some(modifier) = value.get("modifier") {
+        args.push(scalar(modifier, &join(path, "modifier"), "modifier")?);
+    }
+    match value.get("path") {
+        Some(location) => args.push(scalar(location, &join(path, "path"), "path")?),
+        None => return Err(format!("{path}: missing \"path\"")),
+    }
+
+    Ok(args)
+}
+
+fn list<'v>(value: &'v Value, path: &str) -> Result<&'v Vec<Value>, String> {
+    match value {
+        Value::Array(array) => Ok(array),
+        _ => Err(format!("{path}: expected a list")),
+    }
+}
+
+/// Occurrences of the directive `name`, each with its values
+fn directive_values(value: &Value, path: &str, name: &str) -> Result<Vec<Vec<String>>, String> {
+    match value {
+        // flags without value: `internal: true`
+        Value::Bool(on) if is_flag(name) => Ok(if *on { vec![Vec::new()] } else { Vec::new() }),
+        Value::Null => Ok(vec![Vec::new()]),
+        Value::Array(items) if items.iter().all(Value::is_array) && !items.is_empty() => items
+            .iter()
+            .map(|item| list(item, path)?.iter().map(|value| scalar(value, path, name)).collect())
+            .collect(),
+        Value::Array(items) => Ok(vec![items
+            .iter()
+            .map(|value| scalar(value, path, name))
+            .collect::<Result<_, _>>()?]),
+        value => Ok(vec![vec![scalar(value, path, name)?]]),
+    }
+}
+
+fn scalar(value: &Value, path: &str, name: &str) -> Result<String, String> {
+    match value {
+        Value::String(string) => Ok(string.to_owned()),
+        Value::Number(number) => Ok(number.to_string()),
+        Value::Bool(true) => Ok("on".to_owned()),
+        Value::Bool(false) => Ok("off".to_owned()),
+        _ => Err(format!("{path}: expected a value or a list of values for \"{name}\"")),
+    }
+}
+
+fn is_flag(name: &str) -> bool {
+    DIRECTIVES
+        .iter()
+        .any(|directive| directive.name == name && matches!(directive.arity, Arity::Exactly(0)))
+}
+
+fn display(prefix: &str) -> &str {
+    match prefix.is_empty() {
+        true => "document",
+        false => prefix,
+    }
+}
+
+/*------------------------------------------------------------*/
+/*------------------------[ Schema ]--------------------------*/
+/*------------------------------------------------------------*/
+
+/// JSON schema of the documents `load` accepts, generated from the
+/// directive registry (`webserv schema`)
+pub fn schema() -> Value {
+    let servers = json!({ "type": "array", "items": { "$ref": "#/$defs/server" } });
+    let locations = json!({ "type": "array", "items": { "$ref": "#/$defs/location" } });
+
+    let mut main = properties(Context::Main);
+    main.insert("http".to_owned(), json!({ "$ref": "#/$defs/http" }));
+    main.insert("servers".to_owned(), servers.clone());
+
+    let mut http = properties(Context::Http);
+    http.insert("servers".to_owned(), servers);
+
+    let mut server = properties(Context::Server);
+    server.insert("locations".to_owned(), locations.clone());
+
+    let mut location = properties(Context::Location);
+    location.insert("path".to_owned(), json!({ "type": "string" }));
+    location.insert("modifier".to_owned(), json!({ "enum": ["=", "^~", "~", "~*"] }));
+    location.insert("locations".to_owned(), locations);
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "webserv configuration",
+        "type": "object",
+        "properties": main,
+        "additionalProperties": false,
+        "$defs": {
+            "http": { "type": "object", "properties": http, "additionalProperties": false },
+            "server": { "type": "object", "properties": server, "additionalProperties": false },
+            "location": {
+                "type": "object",
+                "properties": location,
+                "required": ["path"],
+                "additionalProperties": false
+            }
+        }
+    })
+}
+
+fn properties(context: Context) -> Map<String, Value> {
+    let mut properties = Map::new();
+
+    properties.insert(
+        "set".to_owned(),
+        json!({
+            "description": "set $name value;",
+            "anyOf": [pair(), { "type": "array", "items": pair() }]
+        }),
+    );
+    for directive in DIRECTIVES.iter().filter(|directive| directive.contexts.contains(&context)) {
+        properties.insert(directive.name.to_owned(), directive_schema(directive));
+    }
+
+    properties
+}
+
+fn pair() -> Value {
+    json!({ "type": "array", "items": { "type": "string" }, "minItems": 2, "maxItems": 2 })
+}
+
+/// A single value, or a list of values when the directive takes several;
+/// a repeatable directive also takes a list of such lists
+fn directive_schema(directive: &Directive) -> Value {
+    let item = match directive.value {
+        directives::Value::Path | directives::Value::Text | directives::Value::Names | directives::Value::Methods => {
+            json!({ "type": "string" })
+        }
+        _ => json!({ "type": ["string", "integer"] }),
+    };
+    let list = |min: usize, max: Option<usize>| {
+        let mut list = json!({ "type": "array", "items": item, "minItems": min });
+        if let Some(max) = max {
+            list["maxItems"] = json!(max);
+        }
+        list
+    };
+
+    let (single, list) = match (directive.value, directive.arity) {
+        (directives::Value::None, _) => (Some(json!({ "type": ["boolean", "null"] })), None),
+        (directives::Value::Flag, _) => (Some(json!({ "type": "boolean" })), Some(json!({ "enum": ["on", "off"] }))),
+        (directives::Value::Strict, _) => (Some(json!({ "type": "boolean" })), Some(json!({ "enum": ["on", "off", "close"] }))),
+        (directives::Value::Cgi, _) => (Some(json!({ "type": "object", "additionalProperties": { "type": "string" } })), None),
+        (_, Arity::Exactly(1)) => (Some(item.clone()), None),
+        (_, Arity::Exactly(count)) => (None, Some(list(count, Some(count)))),
+        (_, Arity::AtLeast(min)) => (Some(item.clone()).filter(|_| min <= 1), Some(list(min, None))),
+        (_, Arity::Between(min, max)) => (Some(item.clone()).filter(|_| min <= 1), Some(list(min, Some(max)))),
+    };
+
+    let mut forms: Vec<Value> = single.into_iter().chain(list.clone()).collect();
+    if let (true, Some(list)) = (directive.is_repeatable(), list) {
+        forms.push(json!({ "type": "array", "items": list, "minItems": 1 }));
+    }
+
+    let mut schema = match forms.len() {
+        1 => forms.remove(0),
+        _ => json!({ "anyOf": forms }),
+    };
+    schema["description"] = json!(format!("{} {};", directive.name, directive.value));
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config_parsing::config;
+    use super::*;
+
+    const TEXT: &str = r#"
+worker_connections 64;
+http {
+    root /srv;
+    error_page 404 /404.html;
+    error_page 500 =200 /500.html;
+    server {
+        listen 8080 default;
+        server_name a.fr b.fr;
+        cgi .py /usr/bin/python3;
+        location ~ \.php$ { internal; }
+        location /api/ { allowed_methods GET POST; auto_index on; }
+    }
+}
+"#;
+
+    const JSON: &str = r#"{
+        "worker_connections": 64,
+        "http": {
+            "root": "/srv",
+            "error_page": [[404, "/404.html"], [500, "=200", "/500.html"]],
+            "servers": [{
+                "listen": [8080, "default"],
+                "server_name": ["a.fr", "b.fr"],
+                "cgi": { ".py": "/usr/bin/python3" },
+                "locations": [
+                    { "modifier": "~", "path": "\\.php$", "internal": true },
+                    { "path": "/api/", "allowed_methods": ["GET", "POST"], "auto_index": true }
+                ]
+            }]
+        }
+    }"#;
+
+    const YAML: &str = r#"
+worker_connections: 64
+http:
+  root: /srv
+  error_page: [[404, /404.html], [500, "=200", /500.html]]
+  servers:
+    - listen: [8080, default]
+      server_name: [a.fr, b.fr]
+      cgi: { .py: /usr/bin/python3 }
+      locations:
+        - { modifier: "~", path: '\.php$', internal: true }
+        - { path: /api/, allowed_methods: [GET, POST], auto_index: on }
+"#;
+
+    const TOML: &str = r#"
+worker_connections = 64
+
+[http]
+root = "/srv"
+error_page = [[404, "/404.html"], [500, "=200", "/500.html"]]
+
+[[http.servers]]
+listen = [8080, "default"]
+server_name = ["a.fr", "b.fr"]
+cgi = { ".py" = "/usr/bin/python3" }
+
+[[http.servers.locations]]
+modifier = "~"
+path = '\.php$'
+internal = true
+
+[[http.servers.locations]]
+path = "/api/"
+allowed_methods = ["GET", "POST"]
+auto_index = true
+"#;
+
+    fn load_str(format: Format, text: &str) -> Result<MainBlock, String> {
+        document(format, text, &HashMap::new())
+    }
+
+    #[test]
+    fn formats_give_the_text_configuration() {
+        let expected = config(TEXT, &HashMap::new()).unwrap().1;
+
+        for (format, text) in [(Format::Json, JSON), (Format::Yaml, YAML), (Format::Toml, TOML)] {
+            assert_eq!(load_str(format, text).unwrap(), expected, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let err = load_str(Format::Json, r#"{ "servers": [{ "listen": 80, "roott": "/srv" }] }"#);
+
+        assert_eq!(
+            err.unwrap_err(),
+            "servers[0].roott: unknown directive \"roott\", did you mean \"root\"?"
+        );
+    }
+
+    #[test]
+    fn value_errors_name_the_key() {
+        let err = load_str(Format::Json, r#"{ "servers": [{ "listen": 80, "keepalive_timeout": "1y" }] }"#);
+        let err = err.unwrap_err();
+        assert!(err.starts_with("servers[0].keepalive_timeout: invalid field"), "{err}");
+
+        let err = load_str(
+            Format::Yaml,
+            "servers:\n  - listen: 80\n    locations:\n      - { path: /a/, client_max_body_size: 1t }\n",
+        );
+        let err = err.unwrap_err();
+        assert!(err.starts_with("servers[0].locations[0].client_max_body_size: invalid"), "{err}");
+    }
+
+    #[test]
+    fn schema_lists_every_directive() {
+        let schema = schema();
+        let blocks = [
+            &schema["properties"],
+            &schema["$defs"]["http"]["properties"],
+            &schema["$defs"]["server"]["properties"],
+            &schema["$defs"]["location"]["properties"],
+        ];
+
+        for directive in DIRECTIVES {
+            let defined = blocks.iter().any(|properties| properties.get(directive.name).is_some());
+            assert!(defined, "{}", directive.name);
+        }
+    }
+}
@@ -1,6 +1,13 @@
-use std::{collections::HashMap, io, path::PathBuf, slice::Iter};
+pub mod parser;
+mod syntax;
+mod variables;
+
+use std::{borrow::Cow, io, path::PathBuf};
+
+use colored::Colorize;
 
 use crate::response::response::ResponseCode;
+use parser::{RequestHead, RequestParser};
 
 /*------------------------------------------------------------------------------------*/
 /*										REQUEST										  */
@@ -35,170 +42,316 @@ impl From<String> for RequestError {
     }
 }
 
+/// A request header, borrowed from the connection buffer it was parsed from
+/// (`RequestParser`). Values are only copied when they are not valid UTF-8 or
+/// when a repeated header is merged.
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
-pub struct Request {
+pub struct Request<'a> {
     method: Method,
-    http_version: String,
+    http_version: Cow<'a, str>,
+    uri: Cow<'a, str>,
     path: PathBuf,
-    query: Option<String>,
-    accept: Option<String>,
-    host: Option<String>,
-    headers: HashMap<String, String>,
+    query: Option<Cow<'a, str>>,
+    accept: Option<Cow<'a, str>>,
+    host: Option<Cow<'a, str>>,
+    /// other headers, names as received
+    headers: Vec<(&'a str, Cow<'a, str>)>,
     content_length: Option<usize>,
-    content_type: Option<String>,
+    content_type: Option<Cow<'a, str>>,
     raw_body: Option<String>,
     raw_header: String,
     state: State,
     keep_connection_alive: bool,
+    keep_alive_requested: bool,
+    transfer_encoding: Vec<String>,
+    absolute_authority: Option<&'a str>,
+    server_name_captures: Vec<(String, String)>,
 }
 
-impl Default for Request {
+impl Default for Request<'_> {
     fn default() -> Self {
         Request {
             query: Option::default(),
             method: Method::default(),
-            http_version: String::default(),
+            http_version: Cow::default(),
+            uri: Cow::default(),
             path: PathBuf::default(),
             accept: Option::default(),
             host: Option::default(),
-            headers: HashMap::default(),
+            headers: Vec::default(),
             content_length: Option::default(),
             content_type: Option::default(),
             raw_body: Option::default(),
             raw_header: String::default(),
             state: State::default(),
             keep_connection_alive: true,
+            keep_alive_requested: false,
+            transfer_encoding: Vec::default(),
+            absolute_authority: Option::default(),
+            server_name_captures: Vec::default(),
         }
     }
 }
 
-impl TryFrom<&[u8]> for Request {
+impl<'a> TryFrom<&'a [u8]> for Request<'a> {
     type Error = ResponseCode;
-    fn try_from(value: &[u8]) -> Result<Request, Self::Error> {
-        let value = String::from_utf8_lossy(value);
-        let headers = value.split("\r\n").map(|str| str.to_string()).collect();
-        match Self::deserialize(headers) {
-            Ok(request) => Ok(request),
-            Err(err) => Err(ResponseCode::new(400, err.as_str())),
+    fn try_from(value: &'a [u8]) -> Result<Request<'a>, Self::Error> {
+        let mut parser = RequestParser::default();
+
+        parser.feed(value);
+        match parser.advance(&HeaderLimits::default())? {
+            // the parser buffer is a copy of `value`, its spans apply to both
+            true => Request::try_from(&RequestHead::new(value, parser.lines())),
+            false => Err(bad_request("incomplete header")),
         }
     }
 }
 
-impl Request {
-    fn deserialize(headers: Vec<String>) -> Result<Self, String> {
-        let mut headers: Iter<'_, String> = headers.iter();
-        let first_line = headers.next();
-        if first_line.is_none() {
-            return Err("empty header".to_owned());
-        }
-
+impl<'a> TryFrom<&RequestHead<'a, '_>> for Request<'a> {
+    type Error = ResponseCode;
+    fn try_from(head: &RequestHead<'a, '_>) -> Result<Request<'a>, Self::Error> {
         let mut request = Request::default();
 
-        request.parse_first_line(first_line.unwrap())?;
-        request.parse_other_lines(headers)?;
+        request.parse_first_line(head.request_line())?;
+        request.parse_other_lines(head.header_lines())?;
+        request.check_framing()?;
 
         Ok(request)
     }
+}
 
-    fn parse_other_lines(&mut self, headers: Iter<'_, String>) -> Result<(), String> {
+/// 400 with its standard reason phrase, the detail only goes to the log
+fn bad_request(detail: &str) -> ResponseCode {
+    eprintln!("{}", format!("Bad request: {detail}").yellow());
+    ResponseCode::from_code(400)
+}
+
+/// Bytes already checked to be visible ASCII (tokens, request target)
+fn ascii(bytes: &[u8]) -> &str {
+    std::str::from_utf8(bytes).unwrap_or_default()
+}
+
+/// `uri` split at the start of its query string
+fn split_query(uri: &str) -> (&str, Option<&str>) {
+    match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    }
+}
+
+impl Request<'static> {
+    /// GET request issued by the server itself for an error page. It is resolved
+    /// on the server's locations without going through `handle_request`, which is
+    /// how it reaches `internal` locations.
+    pub fn subrequest(uri: &str) -> Request<'static> {
+        let uri = match uri.starts_with('/') {
+            true => uri.to_owned(),
+            false => format!("/{uri}"),
+        };
+        let (path, query) = split_query(&uri);
+
+        Request {
+            method: Method::GET,
+            http_version: Cow::Borrowed("HTTP/1.1"),
+            path: PathBuf::from(path),
+            query: query.map(|query| Cow::Owned(query.to_owned())),
+            uri: Cow::Owned(uri),
+            ..Default::default()
+        }
+    }
+}
+
+impl<'a> Request<'a> {
+    fn parse_other_lines(
+        &mut self,
+        headers: impl Iterator<Item = &'a [u8]>,
+    ) -> Result<(), ResponseCode> {
         for header in headers {
-            if header.is_empty() {
-                break;
-            } // end of header
+            self.parse_header_line(header)
+                .map_err(bad_request)?;
+        }
 
-            let split = header.split_once(":");
-            if split.is_none() {
-                return Err(format!("invalid header: {}", header));
-            }
+        Ok(())
+    }
 
-            let name = split.unwrap().0;
-            let value = split.unwrap().1;
+    fn parse_header_line(&mut self, header: &'a [u8]) -> Result<(), &'static str> {
+        if syntax::is_ows(header[0]) {
+            return Err("invalid header: obsolete line folding");
+        }
 
-            match name {
-                "Host" => {
-                    if self.host.is_none() {
-                        self.host = Some(value.trim().to_owned())
-                    } else {
-                        return Err("duplicate header: Host".to_owned());
-                    }
+        let colon = match header.iter().position(|byte| *byte == b':') {
+            Some(colon) => colon,
+            None => return Err("invalid header: missing ':'"),
+        };
+
+        // no whitespace is allowed between the field name and the colon
+        let name = &header[..colon];
+        if !syntax::is_token(name) {
+            return Err("invalid header: invalid field name");
+        }
+
+        let value = syntax::trim_ows(&header[colon + 1..]);
+        if !syntax::is_field_value(value) {
+            return Err("invalid header: invalid field value");
+        }
+
+        // field names are case-insensitive, obs-text values may not be UTF-8
+        let name = ascii(name);
+        let value = String::from_utf8_lossy(value);
+        let known = ["host", "connection", "accept", "content-length", "content-type", "transfer-encoding"]
+            .into_iter()
+            .find(|known| name.eq_ignore_ascii_case(known));
+
+        match known.unwrap_or_default() {
+            "host" => {
+                if self.host.is_none() {
+                    self.host = Some(value)
+                } else {
+                    return Err("duplicate header: Host");
                 }
-                "Connection" => {
-                    if value == "close" {
+            }
+            "connection" => {
+                for option in value.split(',').map(str::trim) {
+                    if option.eq_ignore_ascii_case("close") {
                         self.keep_connection_alive = false
+                    } else if option.eq_ignore_ascii_case("keep-alive") {
+                        self.keep_alive_requested = true
                     }
                 }
-                "Accept" => {
-                    if self.accept.is_none() {
-                        self.accept = Some(value.to_owned())
-                    }
-                    // set if don't exists
-                    else {
-                        self.accept = Some(format!("{} {value}", self.accept.as_ref().unwrap()))
-                    } // concat a space (' ') and the value if already exists
+            }
+            "accept" => match &mut self.accept {
+                Some(accept) => *accept = Cow::Owned(format!("{accept}, {value}")),
+                None => self.accept = Some(value),
+            },
+            "content-length" => {
+                if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+                    return Err("invalid header: Content-Length: invalid value");
                 }
-                "Content-Length" => {
-                    if self.content_length.is_some() {
-                        return Err("invalid header: Content-Length: duplicated header".to_string());
-                    } else {
-                        self.content_length = match value.trim().parse::<usize>() {
-							Ok(len) => Some(len),
-							Err(err) => return Err(format!("invalid header: Content-Length ({value}): failed to convert value: {err}")),
-						};
-                    }
+                let len = match value.parse::<usize>() {
+                    Ok(len) => len,
+                    Err(_) => return Err("invalid header: Content-Length: invalid value"),
+                };
+                if self.content_length.is_some_and(|previous| previous != len) {
+                    return Err("invalid header: Content-Length: conflicting values");
                 }
-                "Content-Type" => {
-                    if self.content_type.is_some() {
-                        return Err("invalid header: Content-type: duplicated header".to_string());
-                    } else {
-                        self.content_type = Some(value.to_string());
-                    }
+                self.content_length = Some(len);
+            }
+            "content-type" => {
+                if self.content_type.is_some() {
+                    return Err("invalid header: Content-type: duplicated header");
+                } else {
+                    self.content_type = Some(value);
                 }
-                _ => {
-                    self.headers
-                        .entry(name.to_owned()) // finding key name
-                        .and_modify(|val|	// modify if exists
-						val.push_str(format!("  {value}").as_str()))
-                        .or_insert(value.to_owned()); // else, insert
+            }
+            "transfer-encoding" => {
+                for coding in value.split(',').map(str::trim) {
+                    self.transfer_encoding.push(coding.to_ascii_lowercase());
                 }
             }
+            // a repeated header is one value, joined with commas
+            _ => match self.headers.iter_mut().find(|(known, _)| known.eq_ignore_ascii_case(name)) {
+                Some((_, previous)) => *previous = Cow::Owned(format!("{previous}, {value}")),
+                None => self.headers.push((name, value)),
+            },
         }
 
         Ok(())
     }
 
-    fn parse_first_line(&mut self, line: &str) -> Result<(), String> {
-        let split: Vec<&str> = line.split_whitespace().collect();
+    fn parse_first_line(&mut self, line: &'a [u8]) -> Result<(), ResponseCode> {
+        // request-line = method SP request-target SP HTTP-version
+        let mut split = line.split(|byte| *byte == b' ');
+        let (method, target, version) = match (split.next(), split.next(), split.next(), split.next()) {
+            (Some(method), Some(target), Some(version), None) if syntax::is_token(method) => (method, target, version),
+            _ => return Err(bad_request("invalid header: first line invalid")),
+        };
+        if !syntax::is_request_target(target) {
+            return Err(bad_request("invalid header: invalid request target"));
+        }
 
-        if split.len() != 3 {
-            return Err(format!("invalid header: first line invalid"));
-        } // Bad Request
+        // method and target have been checked to be visible ASCII
+        let (method, target) = (ascii(method), ascii(target));
+        let version = std::str::from_utf8(version).unwrap_or_default();
 
-        let method = split[0];
         self.method = match Method::try_from_str(method) {
             Ok(method) => method,
             Err(_) => Method::UNKNOWN,
         };
 
-        self.path = PathBuf::from(split[1]);
-        self.http_version = split[2].to_owned();
-        Ok(())
+        match syntax::parse_http_version(version) {
+            Some((1, 0)) | Some((1, 1)) => (),
+            Some(_) => return Err(ResponseCode::from_code(505)),
+            None => return Err(bad_request("invalid header: invalid HTTP version")),
+        }
+        self.http_version = Cow::Borrowed(version);
+
+        self.parse_request_target(target)
     }
 
-    fn add_path(&mut self, path: &str) {
-        if let Some(query_pos) = path.find("?") {
-            self.query = Some(path[query_pos + 1..].to_string());
-            self.path = PathBuf::from(&path[..query_pos]);
-        } else {
-            self.path = PathBuf::from(path)
+    fn parse_request_target(&mut self, target: &'a str) -> Result<(), ResponseCode> {
+        // absolute-form: the authority replaces any Host header
+        let origin = match target
+            .strip_prefix("http://")
+            .or_else(|| target.strip_prefix("https://"))
+        {
+            Some(rest) => {
+                let (authority, path) = match rest.find('/') {
+                    Some(pos) => (&rest[..pos], &rest[pos..]),
+                    None => (rest, "/"),
+                };
+                if authority.is_empty() {
+                    return Err(bad_request("invalid header: invalid request target"));
+                }
+                self.absolute_authority = Some(authority);
+                path
+            }
+            None => target,
+        };
+
+        if !origin.starts_with('/') && (origin != "*" || self.method != Method::OPTIONS) {
+            return Err(bad_request("invalid header: invalid request target"));
         }
+
+        let (path, query) = split_query(origin);
+        self.uri = Cow::Borrowed(origin);
+        self.path = PathBuf::from(path);
+        self.query = query.map(Cow::Borrowed);
+        Ok(())
     }
 
-    pub fn get(&self, header: &str) -> Option<&String> {
-        match self.headers.get(header) {
-            None => None,
-            Some(value) => Some(value),
+    fn check_framing(&mut self) -> Result<(), ResponseCode> {
+        if let Some(authority) = self.absolute_authority.take() {
+            self.host = Some(Cow::Borrowed(authority));
+        } else if self.host.is_none() && self.http_version == "HTTP/1.1" {
+            return Err(bad_request("invalid header: missing Host"));
+        }
+
+        if self.http_version == "HTTP/1.0" && !self.keep_alive_requested {
+            self.keep_connection_alive = false;
+        }
+
+        if self.transfer_encoding.is_empty() {
+            return Ok(());
+        }
+
+        // a message with both Transfer-Encoding and Content-Length is a smuggling attempt
+        if self.content_length.is_some() {
+            return Err(bad_request("invalid header: Transfer-Encoding with Content-Length"));
+        } else if self.http_version == "HTTP/1.0" {
+            return Err(bad_request("invalid header: Transfer-Encoding in HTTP/1.0"));
+        } else if self.transfer_encoding.last().map(String::as_str) != Some("chunked") {
+            return Err(bad_request("invalid header: chunked must be the final transfer coding"));
         }
+
+        Err(ResponseCode::from_code(501)) // chunked request bodies are not supported
+    }
+
+    pub fn get(&self, header: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(header))
+            .map(|(_, value)| value.as_ref())
     }
 
     pub fn state(&self) -> &State {
@@ -213,16 +366,21 @@ impl Request {
         &self.path
     }
 
+    /// Request target as received (path and query), unaffected by root or alias
+    pub fn request_uri(&self) -> &str {
+        &self.uri
+    }
+
     pub fn set_path(&mut self, path: PathBuf) {
         self.path = path
     }
 
-    pub fn accept(&self) -> Option<&String> {
-        self.accept.as_ref()
+    pub fn accept(&self) -> Option<&str> {
+        self.accept.as_deref()
     }
 
-    pub fn host(&self) -> Option<&String> {
-        self.host.as_ref()
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
     }
 
     pub fn content_length(&self) -> Option<&usize> {
@@ -237,16 +395,58 @@ impl Request {
         &self.http_version
     }
 
-    pub fn content_type(&self) -> Option<&String> {
-        self.content_type.as_ref()
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// Headers other than Host, Connection, Accept, Content-Length,
+    /// Content-Type and Transfer-Encoding, names as received
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(name, value)| (*name, value.as_ref()))
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
     }
 
-    pub fn headers(&self) -> &HashMap<String, String> {
-        &self.headers
+    /// Named groups of the regex server_name that selected the server
+    pub fn server_name_captures(&self) -> &[(String, String)] {
+        &self.server_name_captures
     }
 
-    pub fn query(&self) -> Option<&String> {
-        self.query.as_ref()
+    pub fn set_server_name_captures(&mut self, captures: Vec<(String, String)>) {
+        self.server_name_captures = captures
+    }
+}
+
+/*------------------------------------------------------------------------------------*/
+/*									HEADER LIMITS									  */
+/*------------------------------------------------------------------------------------*/
+
+/// Bounds applied to a request header (`client_header_buffer_size`,
+/// `large_client_header_buffers`, `client_max_headers`).
+/// As in nginx, a header is first received in a buffer of `buffer_size` bytes;
+/// past it, each line must fit in one of `large_count` buffers of `line_size` bytes.
+#[derive(Debug, Clone)]
+pub struct HeaderLimits {
+    /// size of the first buffer
+    pub buffer_size: usize,
+    /// number of large buffers
+    pub large_count: usize,
+    /// size of a large buffer, maximum length of a line that does not fit in the first buffer
+    pub line_size: usize,
+    /// maximum number of header lines
+    pub max_count: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        HeaderLimits {
+            buffer_size: 1024,
+            large_count: 4,
+            line_size: 8192,
+            max_count: 100,
+        }
     }
 }
 
@@ -364,6 +564,8 @@ impl State {
     }
 }
 
+pub use utils::extension_to_content_type;
+
 mod utils {
     use lazy_static::lazy_static;
     use std::collections::HashMap;
@@ -489,3 +691,51 @@ mod utils {
 // codes_responses[525] = "SSL Handshake Failed";
 // codes_responses[526] = "Invalid SSL Certificate";
 // codes_responses[527] = "Railgun Error";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request<'_>, ResponseCode> {
+        Request::try_from(raw.as_bytes())
+    }
+
+    fn code(raw: &str) -> u16 {
+        parse(raw).unwrap_err().code()
+    }
+
+    #[test]
+    fn transfer_encoding_with_content_length_is_rejected() {
+        let raw = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+        assert_eq!(code(raw), 400);
+        // chunked alone is well framed, but not supported
+        assert_eq!(code("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n"), 501);
+    }
+
+    #[test]
+    fn obsolete_line_folding_is_rejected() {
+        assert_eq!(code("GET / HTTP/1.1\r\nHost: a\r\nX-Long: a\r\n b\r\n\r\n"), 400);
+    }
+
+    #[test]
+    fn host_is_required_in_http_1_1() {
+        assert_eq!(code("GET / HTTP/1.1\r\n\r\n"), 400);
+        assert!(parse("GET / HTTP/1.0\r\n\r\n").is_ok());
+        // the authority of an absolute target stands for the Host
+        assert_eq!(parse("GET http://a.fr/x HTTP/1.1\r\n\r\n").unwrap().host(), Some("a.fr"));
+    }
+
+    #[test]
+    fn unsupported_versions_get_505() {
+        assert_eq!(code("GET / HTTP/2.0\r\nHost: a\r\n\r\n"), 505);
+        assert_eq!(code("GET / HTTP/1\r\nHost: a\r\n\r\n"), 400);
+    }
+
+    #[test]
+    fn details_stay_out_of_the_reason_phrase() {
+        let err = parse("GET / HTTP/1.1\r\n\r\n").unwrap_err();
+
+        assert_eq!(err.msg(), ResponseCode::from_code(400).msg());
+    }
+}
@@ -0,0 +1,258 @@
+use std::ops::Range;
+
+use crate::response::response::ResponseCode;
+
+use super::{bad_request, HeaderLimits, State};
+
+/*------------------------------------------------------------------------------------*/
+/*									REQUEST PARSER									  */
+/*------------------------------------------------------------------------------------*/
+
+/// Resumable request header parser.
+/// Bytes are scanned once, as they arrive, and every header line is recorded
+/// as a span of the connection buffer instead of being copied.
+#[derive(Debug, Default)]
+pub struct RequestParser {
+    buffer: Vec<u8>,
+    lines: Vec<Range<usize>>,
+    line_start: usize,
+    scanned: usize,
+    header_end: usize,
+    /// end of the buffer the current line is received in (0: the first buffer)
+    buffer_end: usize,
+    /// number of large buffers used
+    large_count: usize,
+    state: State,
+}
+
+/// Borrowed view on a complete request header: the bytes received and the
+/// spans of its lines. `Request` borrows the bytes, not the spans.
+pub struct RequestHead<'a, 'l> {
+    buffer: &'a [u8],
+    lines: &'l [Range<usize>],
+}
+
+impl RequestParser {
+    pub fn with_capacity(capacity: usize) -> Self {
+        RequestParser {
+            buffer: Vec::with_capacity(capacity),
+            ..Default::default()
+        }
+    }
+
+    /// `Undefined` while waiting for a request, `OnHeader` while receiving it,
+    /// `OnBody` once the header is complete
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        self.buffer.extend_from_slice(bytes);
+        if self.state.is(State::Undefined) {
+            self.state = State::OnHeader;
+        }
+    }
+
+    /// Scans the bytes received since the last call, returns true once the header is complete
+    pub fn advance(&mut self, limits: &HeaderLimits) -> Result<bool, ResponseCode> {
+        if self.state.is(State::OnBody) {
+            return Ok(true);
+        }
+
+        while let Some(pos) = self.buffer[self.scanned..].iter().position(|byte| *byte == b'\n') {
+            let lf = self.scanned + pos;
+            self.scanned = lf + 1;
+
+            if lf == self.line_start || self.buffer[lf - 1] != b'\r' {
+                return Err(bad_request("invalid header: bare LF"));
+            }
+
+            self.fit(lf + 1, limits)?;
+
+            let line = self.line_start..lf - 1;
+            self.line_start = lf + 1;
+
+            if line.is_empty() {
+                // empty lines received before the request-line are ignored
+                if self.lines.is_empty() {
+                    continue;
+                }
+
+                self.header_end = self.line_start;
+                self.state = State::OnBody;
+                return Ok(true);
+            }
+
+            self.lines.push(line);
+
+            if self.lines.len() > limits.max_count + 1 {
+                return Err(ResponseCode::from_code(431));
+            }
+        }
+
+        // the line being received can already be too long
+        self.fit(self.buffer.len(), limits).map(|_| false)
+    }
+
+    /// Checks that the current line, received up to `end`, fits in a buffer.
+    /// A line that overflows the first buffer, or a large one, moves whole
+    /// to a new large buffer, as nginx does.
+    fn fit(&mut self, end: usize, limits: &HeaderLimits) -> Result<(), ResponseCode> {
+        let buffer_end = match self.large_count {
+            0 => limits.buffer_size,
+            _ => self.buffer_end,
+        };
+        if end <= buffer_end {
+            return Ok(());
+        }
+
+        if end - self.line_start > limits.line_size {
+            return match self.lines.is_empty() {
+                true => Err(ResponseCode::from_code(414)), // request-line
+                false => Err(ResponseCode::from_code(431)),
+            };
+        }
+
+        self.large_count += 1;
+        self.buffer_end = self.line_start + limits.line_size;
+
+        match self.large_count > limits.large_count {
+            true => Err(ResponseCode::from_code(431)),
+            false => Ok(()),
+        }
+    }
+
+    pub(super) fn lines(&self) -> &[Range<usize>] {
+        &self.lines
+    }
+
+    /// The complete header, and the bytes received after it (start of the
+    /// body or of the next request)
+    pub fn split(&mut self) -> (RequestHead<'_, '_>, &mut [u8]) {
+        let (head, body) = self.buffer.split_at_mut(self.header_end);
+
+        (RequestHead::new(head, &self.lines), body)
+    }
+
+    /// Prepares the parser for the next request, starting with the bytes left by the previous one
+    pub fn reset(&mut self, left: Vec<u8>) {
+        self.buffer = left;
+        self.lines.clear();
+        self.line_start = 0;
+        self.scanned = 0;
+        self.header_end = 0;
+        self.buffer_end = 0;
+        self.large_count = 0;
+        self.state = match self.buffer.is_empty() {
+            true => State::Undefined,
+            false => State::OnHeader,
+        };
+    }
+}
+
+impl<'a, 'l> RequestHead<'a, 'l> {
+    pub fn new(buffer: &'a [u8], lines: &'l [Range<usize>]) -> Self {
+        RequestHead { buffer, lines }
+    }
+
+    pub fn request_line(&self) -> &'a [u8] {
+        &self.buffer[self.lines[0].clone()]
+    }
+
+    pub fn header_lines(&self) -> impl Iterator<Item = &'a [u8]> + 'l
+    where
+        'a: 'l,
+    {
+        let buffer = self.buffer;
+        self.lines[1..].iter().map(move |line| &buffer[line.clone()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::{Method, Request};
+
+    const LIMITS: HeaderLimits = HeaderLimits { buffer_size: 16, large_count: 2, line_size: 32, max_count: 2 };
+
+    /// Feeds `segments` one read at a time, returns the result of the last `advance`
+    fn advance(parser: &mut RequestParser, segments: &[&str]) -> Result<bool, ResponseCode> {
+        let mut done = Ok(false);
+        for segment in segments {
+            parser.feed(segment.as_bytes());
+            done = parser.advance(&LIMITS);
+        }
+        done
+    }
+
+    fn code(segments: &[&str]) -> u16 {
+        advance(&mut RequestParser::default(), segments).unwrap_err().code()
+    }
+
+    #[test]
+    fn head_split_across_reads() {
+        let mut parser = RequestParser::default();
+
+        assert!(advance(&mut parser, &["GET /a?b HT", "TP/1.1\r\nHost: x\r", "\n\r\nbody"]).unwrap());
+
+        let (head, body) = parser.split();
+        let request = Request::try_from(&head).unwrap();
+        assert_eq!(request.method(), &Method::GET);
+        assert_eq!(request.request_uri(), "/a?b");
+        assert_eq!(request.query(), Some("b"));
+        assert_eq!(request.host(), Some("x"));
+        assert_eq!(body, b"body");
+    }
+
+    #[test]
+    fn incomplete_head_waits_for_more() {
+        let mut parser = RequestParser::default();
+
+        assert!(!advance(&mut parser, &["\r\nGET / HTTP/1.1\r\n", "Host: x\r\n"]).unwrap());
+        assert!(parser.state().is(State::OnHeader));
+    }
+
+    #[test]
+    fn request_line_too_long_is_414() {
+        let target = "a".repeat(40);
+
+        // rejected before the line is complete
+        assert_eq!(code(&[&format!("GET /{target}")]), 414);
+        assert_eq!(code(&["GET /", &target, " HTTP/1.1\r\n"]), 414);
+    }
+
+    #[test]
+    fn header_limits_are_431() {
+        let long = format!("X: {}\r\n", "b".repeat(40));
+
+        assert_eq!(code(&["GET / HTTP/1.1\r\n", &long]), 431);
+        assert_eq!(code(&["GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n"]), 431);
+        // every line fits in a large buffer, but a third one is needed
+        let header = "X-A: 012345678901234567890123\r\n";
+        assert_eq!(code(&["GET / HTTP/1.1\r\n", header, header, "X-C: 0"]), 431);
+    }
+
+    #[test]
+    fn lines_spill_from_the_first_buffer_into_large_ones() {
+        let mut parser = RequestParser::default();
+
+        // a request-line longer than line_size is accepted in the first buffer
+        let limits = HeaderLimits { buffer_size: 64, line_size: 8, ..LIMITS };
+        parser.feed(b"GET /0123456789 HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(parser.advance(&limits).unwrap());
+
+        // first buffer, then two large buffers: the request-line, then both headers
+        let mut parser = RequestParser::default();
+        parser.feed(b"\r\nGET /0123456789 HTTP/1.1\r\nHost: x\r\nX-A: 0123456789\r\n\r\n");
+        assert!(parser.advance(&LIMITS).unwrap());
+    }
+
+    #[test]
+    fn bare_lf_is_rejected() {
+        assert_eq!(code(&["GET / HTTP/1.1\nHost: x\r\n\r\n"]), 400);
+    }
+}
@@ -0,0 +1,56 @@
+/*------------------------------------------------------------------------------------*/
+/*									RFC 9112 SYNTAX									  */
+/*------------------------------------------------------------------------------------*/
+
+// tchar = "!" / "#" / "$" / "%" / "&" / "'" / "*" / "+" / "-" / "." / "^" / "_" / "`" / "|" / "~" / DIGIT / ALPHA
+pub fn is_tchar(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+pub fn is_token(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && bytes.iter().all(|byte| is_tchar(*byte))
+}
+
+// field-value = *( VCHAR / obs-text / SP / HTAB )
+pub fn is_field_value(bytes: &[u8]) -> bool {
+    bytes
+        .iter()
+        .all(|byte| matches!(byte, b' ' | b'\t' | 0x21..=0x7e | 0x80..=0xff))
+}
+
+// request-target: visible ASCII only, no whitespace nor control characters
+pub fn is_request_target(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && bytes.iter().all(|byte| matches!(byte, 0x21..=0x7e))
+}
+
+pub fn is_ows(byte: u8) -> bool {
+    byte == b' ' || byte == b'\t'
+}
+
+pub fn trim_ows(mut bytes: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = bytes {
+        if !is_ows(*first) {
+            break;
+        }
+        bytes = rest;
+    }
+    while let [rest @ .., last] = bytes {
+        if !is_ows(*last) {
+            break;
+        }
+        bytes = rest;
+    }
+    bytes
+}
+
+/// HTTP-version = "HTTP/" DIGIT "." DIGIT, returns (major, minor)
+pub fn parse_http_version(version: &str) -> Option<(u8, u8)> {
+    let digits = version.strip_prefix("HTTP/")?.as_bytes();
+
+    match digits {
+        [major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+            Some((major - b'0', minor - b'0'))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,68 @@
+use super::Request;
+
+/*------------------------------------------------------------------------------------*/
+/*									VARIABLES										  */
+/*------------------------------------------------------------------------------------*/
+
+impl Request<'_> {
+    /// Replaces the nginx-like variables ($host, $request_uri, ...) found in `value`.
+    /// Unknown variables are left untouched.
+    pub fn expand_variables(&self, value: &str) -> String {
+        let mut expanded = String::with_capacity(value.len());
+        let mut rest = value;
+
+        while let Some(dollar) = rest.find('$') {
+            expanded.push_str(&rest[..dollar]);
+            rest = &rest[dollar + 1..];
+
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let name = &rest[..len];
+
+            match self.variable(name) {
+                Some(value) => expanded.push_str(&value),
+                None => {
+                    expanded.push('$');
+                    expanded.push_str(name);
+                }
+            }
+            rest = &rest[len..];
+        }
+
+        expanded.push_str(rest);
+        expanded
+    }
+
+    pub fn variable(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "scheme" => "http".to_owned(),
+            "request_method" => self.method().to_string(),
+            "request_uri" => self.request_uri().to_owned(),
+            "uri" => self.path().to_string_lossy().into_owned(),
+            "args" | "query_string" => self.query().map(str::to_owned).unwrap_or_default(),
+            "http_host" => self.host().map(str::to_owned).unwrap_or_default(),
+            "host" => self.hostname().unwrap_or_default(),
+            // named captures of a regex server_name: ~^(?<sub>.+)\.example\.com$ gives $sub
+            _ => {
+                let (_, value) = self.server_name_captures.iter().find(|(capture, _)| capture == name)?;
+                value.clone()
+            }
+        };
+
+        Some(value)
+    }
+
+    /// Host header without port nor trailing dot, lowercased
+    pub fn hostname(&self) -> Option<String> {
+        let host = self.host()?;
+
+        let name = match host.starts_with('[') {
+            // IPv6 literal: [::1]:8080
+            true => host.find(']').map_or(host, |end| &host[..=end]),
+            false => host.split(':').next().unwrap_or(host),
+        };
+
+        Some(name.trim_end_matches('.').to_ascii_lowercase())
+    }
+}
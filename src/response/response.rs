@@ -10,10 +10,9 @@ use lazy_static::lazy_static;
 use tokio::{
     fs::File,
     io::{self, AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
 };
 
-use crate::request::Method;
+use crate::{listener::stream::ClientStream, request::Method};
 
 #[derive(Default, Debug)]
 pub struct Response {
@@ -34,7 +33,7 @@ impl Response {
 
     pub async fn send(
         &mut self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         buffer: &mut [u8; 8196],
     ) -> io::Result<()> {
         match self.send_header(stream).await {
@@ -50,7 +49,10 @@ impl Response {
         Ok(())
     }
 
-    async fn send_header(&mut self, stream: &mut TcpStream) -> Result<(), Option<Error>> {
+    async fn send_header(&mut self, stream: &mut ClientStream) -> Result<(), Option<Error>> {
+        if stream.closing() {
+            self.headers.insert("Connection".to_owned(), "close".to_owned());
+        }
         let header = self.serialize_header().await;
 
         let buffer = header.as_bytes();
@@ -65,7 +67,7 @@ impl Response {
 
     async fn send_body(
         &mut self,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         buffer: &mut [u8; 8196],
     ) -> io::Result<()> {
 
@@ -221,7 +223,7 @@ impl ResponseCode {
                 ErrorKind::NotFound => 404,          // Not Found
                 ErrorKind::PermissionDenied => 403,  // Forbidden
                 ErrorKind::ConnectionRefused => 503, // Service Unavailable
-                ErrorKind::TimedOut => 524,          // a Timeout occured
+                ErrorKind::TimedOut => 408,          // Request Timeout
                 ErrorKind::WriteZero => 500,         // Internal Server Error
                 ErrorKind::Interrupted => 500,       // Internal Server Error
                 _ => 500,                            // Default to Internal Server Error
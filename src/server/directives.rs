@@ -0,0 +1,370 @@
+/*---------------------------------------------------------------*/
+/*------------------------[ DIRECTIVES ]-------------------------*/
+/*---------------------------------------------------------------*/
+
+use std::{collections::HashMap, fmt, net::IpAddr, path::PathBuf, time::Duration};
+
+use crate::request::Method;
+
+use super::{
+    parsing,
+    server_names::Strict,
+    traits::settings::{Applied, Settings},
+};
+
+/// Block a directive appears in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Context {
+    Main,
+    Http,
+    Server,
+    Location,
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Context::Main => write!(f, "main context"),
+            Context::Http => write!(f, "http"),
+            Context::Server => write!(f, "server"),
+            Context::Location => write!(f, "location"),
+        }
+    }
+}
+
+/// Number of values a directive takes
+#[derive(Clone, Copy, Debug)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+    Between(usize, usize),
+}
+
+impl Arity {
+    fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exactly(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+            Arity::Between(min, max) => min <= count && count <= max,
+        }
+    }
+}
+
+/// Kind of values a directive takes, shown when they are wrong
+#[derive(Clone, Copy, Debug)]
+pub enum Value {
+    None,
+    Flag,
+    Number,
+    Size,
+    Duration,
+    Path,
+    Text,
+    Methods,
+    Names,
+    Listen,
+    Strict,
+    Return,
+    ErrorPages,
+    LimitConn,
+    Buffers,
+    Cgi,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let syntax = match self {
+            Value::None => "no value",
+            Value::Flag => "on | off",
+            Value::Number => "number",
+            Value::Size => "size",
+            Value::Duration => "time",
+            Value::Path => "path",
+            Value::Text => "text",
+            Value::Methods => "method ...",
+            Value::Names => "name ...",
+            Value::Listen => "[address:]port [default]",
+            Value::Strict => "on | off | close",
+            Value::Return => "code [text | URL] | URL",
+            Value::ErrorPages => "code [=[response]] uri ...",
+            Value::LimitConn => "ip | server number",
+            Value::Buffers => "number size",
+            Value::Cgi => ".extension path",
+        };
+        write!(f, "{syntax}")
+    }
+}
+
+type Apply = fn(&mut dyn Settings, &str, Vec<String>) -> Result<(), String>;
+
+pub struct Directive {
+    pub name: &'static str,
+    pub contexts: &'static [Context],
+    pub arity: Arity,
+    pub value: Value,
+    apply: Apply,
+}
+
+const MAIN: &[Context] = &[Context::Main];
+const SERVER: &[Context] = &[Context::Server];
+const HTTP_SERVER: &[Context] = &[Context::Http, Context::Server];
+const BOTH: &[Context] = &[Context::Server, Context::Location];
+const ALL: &[Context] = &[Context::Http, Context::Server, Context::Location];
+const LOCATION: &[Context] = &[Context::Location];
+
+/// Every directive the configuration accepts
+pub const DIRECTIVES: &[Directive] = &[
+    // main context
+    directive("worker_connections", MAIN, Arity::Exactly(1), Value::Number, |target, name, values| {
+        parsing::extract_count(name, values).and_then(|max| target.set_worker_connections(max))
+    }),
+    directive("pid", MAIN, Arity::Exactly(1), Value::Path, |target, _, values| {
+        parsing::extract_root(values).and_then(|path| target.set_pid(path))
+    }),
+    // server (and http for the defaults of every server)
+    directive("listen", SERVER, Arity::Between(1, 2), Value::Listen, |target, _, values| {
+        parsing::extract_listen(values).and_then(|(address, port, default)| target.set_listen(address, port, default))
+    }),
+    directive("server_name", SERVER, Arity::AtLeast(1), Value::Names, |target, _, values| {
+        target.add_server_names(values)
+    }),
+    directive("server_names", SERVER, Arity::AtLeast(1), Value::Names, |target, _, values| {
+        target.add_server_names(values)
+    }),
+    directive("strict_server_name", HTTP_SERVER, Arity::Exactly(1), Value::Strict, |target, _, values| {
+        parsing::extract_strict_server_name(values).and_then(|strict| target.set_strict_server_name(strict))
+    }),
+    directive("limit_conn", HTTP_SERVER, Arity::Exactly(2), Value::LimitConn, |target, _, values| {
+        parsing::extract_limit_conn(values).and_then(|(key, max)| target.set_limit_conn(key, max))
+    }),
+    directive("client_header_timeout", HTTP_SERVER, Arity::Exactly(1), Value::Duration, |target, name, values| {
+        parsing::extract_timeout(name, values).and_then(|timeout| target.set_client_header_timeout(timeout))
+    }),
+    directive("keepalive_timeout", HTTP_SERVER, Arity::Exactly(1), Value::Duration, |target, name, values| {
+        parsing::extract_timeout(name, values).and_then(|timeout| target.set_keepalive_timeout(timeout))
+    }),
+    directive("keepalive_requests", HTTP_SERVER, Arity::Exactly(1), Value::Number, |target, name, values| {
+        parsing::extract_count(name, values).and_then(|max| target.set_keepalive_requests(max))
+    }),
+    directive("client_header_buffer_size", HTTP_SERVER, Arity::Exactly(1), Value::Size, |target, name, values| {
+        parsing::extract_size(name, values).and_then(|size| target.set_client_header_buffer_size(size))
+    }),
+    directive("large_client_header_buffers", HTTP_SERVER, Arity::Exactly(2), Value::Buffers, |target, _, values| {
+        parsing::extract_large_client_header_buffers(values)
+            .and_then(|buffers| target.set_large_client_header_buffers(buffers))
+    }),
+    directive("client_max_headers", HTTP_SERVER, Arity::Exactly(1), Value::Number, |target, name, values| {
+        parsing::extract_count(name, values).and_then(|max| target.set_client_max_headers(max))
+    }),
+    // http, server and location
+    directive("root", ALL, Arity::Exactly(1), Value::Path, |target, _, values| {
+        parsing::extract_root(values).and_then(|root| target.set_root(root))
+    }),
+    directive("upload_folder", ALL, Arity::Exactly(1), Value::Path, |target, _, values| {
+        parsing::extract_upload_folder(values).and_then(|folder| target.set_upload_folder(folder))
+    }),
+    directive("file_upload", ALL, Arity::Exactly(1), Value::Flag, |target, name, values| {
+        parsing::extract_flag(name, values).and_then(|on| target.set_file_upload(on))
+    }),
+    directive("index", ALL, Arity::Exactly(1), Value::Path, |target, _, values| {
+        parsing::extract_index(values).and_then(|index| target.set_index(index))
+    }),
+    directive("auto_index", ALL, Arity::Exactly(1), Value::Flag, |target, name, values| {
+        parsing::extract_flag(name, values).and_then(|on| target.set_auto_index(on))
+    }),
+    directive("client_max_body_size", ALL, Arity::Exactly(1), Value::Size, |target, _, values| {
+        parsing::extract_max_body_size(values).and_then(|size| target.set_max_body_size(size))
+    }),
+    directive("charset", ALL, Arity::Exactly(1), Value::Text, |target, _, mut values| {
+        target.set_charset(values.remove(0))
+    }),
+    directive("cgi", ALL, Arity::Exactly(2), Value::Cgi, |target, _, values| {
+        parsing::extract_cgi(values).and_then(|(extension, path)| target.add_cgi(extension, path))
+    }),
+    directive("allowed_methods", ALL, Arity::AtLeast(1), Value::Methods, |target, _, values| {
+        extract_methods(values).and_then(|methods| target.add_methods(methods))
+    }),
+    directive("error_page", ALL, Arity::AtLeast(2), Value::ErrorPages, |target, _, values| {
+        let (pages, redirects) = parsing::extract_error_page(values)?;
+        target.add_error_pages(pages.unwrap_or_default())?;
+        target.add_error_redirects(redirects.unwrap_or_default())
+    }),
+    directive("client_body_timeout", ALL, Arity::Exactly(1), Value::Duration, |target, name, values| {
+        parsing::extract_timeout(name, values).and_then(|timeout| target.set_client_body_timeout(timeout))
+    }),
+    directive("send_timeout", ALL, Arity::Exactly(1), Value::Duration, |target, name, values| {
+        parsing::extract_timeout(name, values).and_then(|timeout| target.set_send_timeout(timeout))
+    }),
+    // server and location
+    directive("return", BOTH, Arity::Between(1, 2), Value::Return, |target, _, values| {
+        parsing::extract_return(values).and_then(|return_| target.set_return(return_))
+    }),
+    directive("internal", BOTH, Arity::Exactly(0), Value::None, |target, _, _| {
+        target.set_internal()
+    }),
+    // location
+    directive("alias", LOCATION, Arity::Exactly(1), Value::Path, |target, _, values| {
+        let regex = target.is_regex_location();
+        parsing::extract_alias(values, regex).and_then(|alias| target.set_alias(alias))
+    }),
+    directive("redirect", LOCATION, Arity::Exactly(1), Value::Text, |target, _, mut values| {
+        target.set_redirect(values.remove(0))
+    }),
+];
+
+impl Directive {
+    /// Values of a repeated directive add up, the other ones are set once per block
+    pub fn is_repeatable(&self) -> bool {
+        matches!(self.value, Value::Names | Value::Methods | Value::ErrorPages)
+    }
+}
+
+const fn directive(
+    name: &'static str,
+    contexts: &'static [Context],
+    arity: Arity,
+    value: Value,
+    apply: Apply,
+) -> Directive {
+    Directive {
+        name,
+        contexts,
+        arity,
+        value,
+        apply,
+    }
+}
+
+/// Checks `name` against the registry and applies its values to `target`
+pub fn apply(
+    target: &mut dyn Settings,
+    context: Context,
+    name: &str,
+    values: Vec<String>,
+) -> Result<(), String> {
+    let directive = check(context, name, values.len())?;
+
+    (directive.apply)(target, name, values)
+}
+
+/// `check`, then the values are applied to a target that keeps nothing, so that
+/// their errors are reported where the directive is written
+pub fn validate(
+    context: Context,
+    name: &str,
+    values: Vec<String>,
+    regex_location: bool,
+) -> Result<&'static Directive, String> {
+    let directive = check(context, name, values.len())?;
+
+    (directive.apply)(&mut Discard { regex_location }, name, values)?;
+    Ok(directive)
+}
+
+/// Fails if `name` is unknown, not allowed in `context` or given a wrong number of values
+pub fn check(context: Context, name: &str, count: usize) -> Result<&'static Directive, String> {
+    let directive = match DIRECTIVES.iter().find(|directive| directive.name == name) {
+        Some(directive) => directive,
+        None => {
+            return Err(match suggest(name) {
+                Some(known) => format!("unknown directive \"{name}\", did you mean \"{known}\"?"),
+                None => format!("unknown directive \"{name}\""),
+            })
+        }
+    };
+
+    if !directive.contexts.contains(&context) {
+        return Err(format!("\"{name}\" directive is not allowed in {context}"));
+    }
+
+    if !directive.arity.accepts(count) {
+        return Err(format!(
+            "invalid number of arguments in \"{name}\" directive, expected \"{name} {};\"",
+            directive.value
+        ));
+    }
+
+    Ok(directive)
+}
+
+/// Closest known directive, if `name` looks like a typo of it
+fn suggest(name: &str) -> Option<&'static str> {
+    let max_distance = (name.len() / 3).max(2);
+
+    DIRECTIVES
+        .iter()
+        .map(|directive| (directive.name, edit_distance(name, directive.name)))
+        .filter(|(known, distance)| *distance <= max_distance || known.starts_with(name))
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(known, _)| known)
+}
+
+/// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+fn extract_methods(values: Vec<String>) -> Result<Vec<Method>, String> {
+    values
+        .iter()
+        .map(|method| match Method::try_from_str(method) {
+            Ok(method) => Ok(method),
+            Err(err) => Err(format!("invalid field: allowed_methods: {err}: {method}")),
+        })
+        .collect()
+}
+
+/*---------------------------------------------------------------*/
+/*-------------------------[ DISCARD ]---------------------------*/
+/*---------------------------------------------------------------*/
+
+/// Accepts every value, see `validate`
+struct Discard {
+    regex_location: bool,
+}
+
+impl Settings for Discard {
+    fn is_regex_location(&self) -> bool /*--------------------------------------------------*/ { self.regex_location }
+
+    fn set_root(&mut self, _: PathBuf) -> Applied /*----------------------------------------*/ { Ok(()) }
+    fn set_upload_folder(&mut self, _: PathBuf) -> Applied /*-------------------------------*/ { Ok(()) }
+    fn set_file_upload(&mut self, _: bool) -> Applied /*------------------------------------*/ { Ok(()) }
+    fn set_index(&mut self, _: String) -> Applied /*----------------------------------------*/ { Ok(()) }
+    fn set_auto_index(&mut self, _: bool) -> Applied /*-------------------------------------*/ { Ok(()) }
+    fn set_max_body_size(&mut self, _: usize) -> Applied /*---------------------------------*/ { Ok(()) }
+    fn set_charset(&mut self, _: String) -> Applied /*--------------------------------------*/ { Ok(()) }
+    fn add_cgi(&mut self, _: String, _: PathBuf) -> Applied /*------------------------------*/ { Ok(()) }
+    fn add_methods(&mut self, _: Vec<Method>) -> Applied /*---------------------------------*/ { Ok(()) }
+    fn set_return(&mut self, _: (u16, Option<String>)) -> Applied /*------------------------*/ { Ok(()) }
+    fn add_error_pages(&mut self, _: HashMap<u16, String>) -> Applied /*--------------------*/ { Ok(()) }
+    fn add_error_redirects(&mut self, _: HashMap<u16, (Option<u16>, String)>) -> Applied /*-*/ { Ok(()) }
+    fn set_internal(&mut self) -> Applied /*------------------------------------------------*/ { Ok(()) }
+    fn set_client_body_timeout(&mut self, _: Duration) -> Applied /*------------------------*/ { Ok(()) }
+    fn set_send_timeout(&mut self, _: Duration) -> Applied /*-------------------------------*/ { Ok(()) }
+    fn set_alias(&mut self, _: PathBuf) -> Applied /*---------------------------------------*/ { Ok(()) }
+    fn set_redirect(&mut self, _: String) -> Applied /*-------------------------------------*/ { Ok(()) }
+    fn set_listen(&mut self, _: Option<IpAddr>, _: u16, _: bool) -> Applied /*--------------*/ { Ok(()) }
+    fn add_server_names(&mut self, _: Vec<String>) -> Applied /*----------------------------*/ { Ok(()) }
+    fn set_strict_server_name(&mut self, _: Strict) -> Applied /*---------------------------*/ { Ok(()) }
+    fn set_limit_conn(&mut self, _: String, _: usize) -> Applied /*-------------------------*/ { Ok(()) }
+    fn set_client_header_timeout(&mut self, _: Duration) -> Applied /*----------------------*/ { Ok(()) }
+    fn set_keepalive_timeout(&mut self, _: Duration) -> Applied /*--------------------------*/ { Ok(()) }
+    fn set_keepalive_requests(&mut self, _: usize) -> Applied /*----------------------------*/ { Ok(()) }
+    fn set_client_header_buffer_size(&mut self, _: usize) -> Applied /*---------------------*/ { Ok(()) }
+    fn set_large_client_header_buffers(&mut self, _: (usize, usize)) -> Applied /*----------*/ { Ok(()) }
+    fn set_client_max_headers(&mut self, _: usize) -> Applied /*----------------------------*/ { Ok(()) }
+    fn set_worker_connections(&mut self, _: usize) -> Applied /*----------------------------*/ { Ok(()) }
+    fn set_pid(&mut self, _: PathBuf) -> Applied /*----------------------------------------*/ { Ok(()) }
+}
@@ -0,0 +1,55 @@
+/*---------------------------------------------------------------*/
+/*-------------------------[ GLOBALS ]---------------------------*/
+/*---------------------------------------------------------------*/
+
+use std::{collections::HashMap, path::PathBuf};
+
+use super::{
+    directives::{self, Context},
+    traits::settings::{Applied, Settings},
+};
+
+pub const DEFAULT_WORKER_CONNECTIONS: usize = 1024;
+pub const DEFAULT_PID_FILE: &str = "webserv.pid";
+
+/// Directives set outside of any `server` block.
+#[derive(Clone, Debug)]
+pub struct Globals {
+    worker_connections: usize,
+    pid: PathBuf,
+}
+
+impl Default for Globals {
+    fn default() -> Self {
+        Globals {
+            worker_connections: DEFAULT_WORKER_CONNECTIONS,
+            pid: PathBuf::from(DEFAULT_PID_FILE),
+        }
+    }
+}
+
+impl Globals {
+    pub fn new(directives: HashMap<String, Vec<String>>) -> Result<Self, String> {
+        let mut globals = Globals::default();
+
+        for (name, values) in directives {
+            directives::apply(&mut globals, Context::Main, &name, values)?;
+        }
+
+        Ok(globals)
+    }
+
+    pub fn worker_connections(&self) -> usize {
+        self.worker_connections
+    }
+
+    /// File holding the pid of the running instance, for `-s`
+    pub fn pid(&self) -> &PathBuf {
+        &self.pid
+    }
+}
+
+impl Settings for Globals {
+    fn set_worker_connections(&mut self, max: usize) -> Applied /*--*/ { self.worker_connections = max; Ok(()) }
+    fn set_pid(&mut self, path: PathBuf) -> Applied /*--------------*/ { self.pid = path; Ok(()) }
+}
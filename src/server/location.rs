@@ -2,25 +2,36 @@
 /*-------------------------[ LOCATIONS ]-------------------------*/
 /*---------------------------------------------------------------*/
 
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use regex::{Regex, RegexBuilder};
 
 use crate::{request::Method, LocationBlock};
 
 use super::{
-    parsing,
+    directives::{self, Context},
+    routing::Routes,
     server::Server,
-    traits::{config::Config, handler::Handler},
+    traits::{config::Config, handler::Handler, settings::{Applied, Settings}},
 };
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 #[allow(dead_code)]
 pub struct Location {
     internal: bool,
-    exact_path: bool,
+    modifier: Modifier,
+    regex: Option<Regex>,
     auto_index: bool,
     path: PathBuf,
     root: Option<PathBuf>,
     upload_folder: Option<PathBuf>,
+    file_upload: Option<bool>,
     alias: Option<PathBuf>,
     max_body_size: Option<usize>,
     redirect: Option<String>,
@@ -31,8 +42,14 @@ pub struct Location {
     error_redirect: HashMap<u16, (Option<u16>, String)>,
     cgi: HashMap<String, PathBuf>,
     none_cgi: bool,
-    infos: HashMap<String, Vec<String>>,
-    server: Option<Arc<Server>>,
+    charset: Option<String>,
+    /// back-reference to the server holding the location, for subrequests
+    server: Option<Weak<Server>>,
+    client_body_timeout: Option<Duration>,
+    send_timeout: Option<Duration>,
+    alias_base: Option<PathBuf>,
+    locations: Vec<Location>,
+    routes: Routes,
 }
 
 impl Handler for Location {}
@@ -43,32 +60,124 @@ impl Config for Location {
     fn auto_index(&self) -> bool /*-------------------------------*/ { self.auto_index }
     fn is_location(&self) -> bool /*------------------------------*/ { false }
     fn index(&self) -> Option<&String> /*-------------------------*/ { self.index.as_ref() }
+    fn charset(&self) -> Option<&String> /*-----------------------*/ { self.charset.as_ref() }
     fn root(&self) -> Option<&PathBuf> /*-------------------------*/ { self.root.as_ref() }
     fn alias(&self) -> Option<&PathBuf> /*------------------------*/ { self.alias.as_ref() }
     fn methods(&self) -> Option<&Vec<Method>> /*------------------*/ { self.methods.as_ref() }
     fn max_body_size(&self) -> Option<&usize> /*------------------*/ { self.max_body_size.as_ref() }
     fn cgi(&self) -> &HashMap<String, PathBuf> /*-----------------*/ { &self.cgi }
     fn upload_folder(&self) -> Option<&PathBuf> /*----------------*/ { self.upload_folder.as_ref() }
+    fn file_upload(&self) -> Option<bool> /*----------------------*/ { self.file_upload }
     fn error_pages(&self) -> &HashMap<u16, String> /*-------------*/ { &self.error_pages }
     fn return_(&self) -> Option<&(u16, Option<String>)> /*--------*/ { self.return_.as_ref() }
     fn error_redirect(&self) -> &HashMap<u16, (Option<u16>, String)> { &self.error_redirect }
     fn port(&self) -> Option<&u16> /*-----------------------------*/ { None }
     fn name(&self) -> Option<&Vec<String>> /*---------------------*/ { None }
-    fn locations(&self) -> Option<&HashMap<PathBuf, Location>> /*-*/ { None }
+    fn locations(&self) -> Option<&Vec<Location>> /*-------------*/ { Some(&self.locations) }
+    fn client_body_timeout(&self) -> Option<&Duration> /*---------*/ { self.client_body_timeout.as_ref() }
+    fn send_timeout(&self) -> Option<&Duration> /*----------------*/ { self.send_timeout.as_ref() }
+    fn server(&self) -> Option<Arc<Server>> /*--------------------*/ { self.server.as_ref()?.upgrade() }
+    fn regex(&self) -> Option<&Regex> /*--------------------------*/ { self.regex.as_ref() }
+    fn routes(&self) -> Option<&Routes> /*------------------------*/ { Some(&self.routes) }
+    fn alias_base(&self) -> Option<&PathBuf> /*-------------------*/ { self.alias_base.as_ref() }
+}
+
+impl Settings for Location {
+    fn is_regex_location(&self) -> bool /*------------------------------------*/ { self.modifier.is_regex() }
+
+    fn set_root(&mut self, root: PathBuf) -> Applied /*-----------------------*/ { self.root = Some(root); Ok(()) }
+    fn set_alias(&mut self, alias: PathBuf) -> Applied /*---------------------*/ { self.alias = Some(alias); Ok(()) }
+    fn set_upload_folder(&mut self, folder: PathBuf) -> Applied /*------------*/ { self.upload_folder = Some(folder); Ok(()) }
+    fn set_file_upload(&mut self, on: bool) -> Applied /*---------------------*/ { self.file_upload = Some(on); Ok(()) }
+    fn set_index(&mut self, index: String) -> Applied /*----------------------*/ { self.index = Some(index); Ok(()) }
+    fn set_auto_index(&mut self, on: bool) -> Applied /*----------------------*/ { self.auto_index = on; Ok(()) }
+    fn set_max_body_size(&mut self, size: usize) -> Applied /*----------------*/ { self.max_body_size = Some(size); Ok(()) }
+    fn set_charset(&mut self, charset: String) -> Applied /*------------------*/ { self.charset = Some(charset); Ok(()) }
+    fn add_cgi(&mut self, extension: String, path: PathBuf) -> Applied /*-----*/ { self.cgi.insert(extension, path); Ok(()) }
+    fn add_methods(&mut self, methods: Vec<Method>) -> Applied /*-------------*/ { self.methods.get_or_insert_with(Vec::new).extend(methods); Ok(()) }
+    fn set_return(&mut self, return_: (u16, Option<String>)) -> Applied /*----*/ { self.return_ = Some(return_); Ok(()) }
+    fn set_redirect(&mut self, url: String) -> Applied /*---------------------*/ { self.redirect = Some(url); Ok(()) }
+    fn add_error_pages(&mut self, pages: HashMap<u16, String>) -> Applied /*--*/ { self.error_pages.extend(pages); Ok(()) }
+    fn add_error_redirects(&mut self, redirects: HashMap<u16, (Option<u16>, String)>) -> Applied { self.error_redirect.extend(redirects); Ok(()) }
+    fn set_internal(&mut self) -> Applied /*----------------------------------*/ { self.internal = true; Ok(()) }
+    fn set_client_body_timeout(&mut self, timeout: Duration) -> Applied /*----*/ { self.client_body_timeout = Some(timeout); Ok(()) }
+    fn set_send_timeout(&mut self, timeout: Duration) -> Applied /*-----------*/ { self.send_timeout = Some(timeout); Ok(()) }
+}
+
+/// `location [modifier] path`, decides how the path is matched against a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Modifier {
+    #[default]
+    Prefix, //-------- location /path
+    Exact, //--------- location = /path
+    PriorityPrefix, // location ^~ /path (skips regexes when it is the longest prefix)
+    Regex, //--------- location ~ pattern
+    RegexCaseless, //- location ~* pattern
+}
+
+impl TryFrom<Option<&str>> for Modifier {
+    type Error = String;
+    fn try_from(value: Option<&str>) -> Result<Self, Self::Error> {
+        match value {
+            None => Ok(Modifier::Prefix),
+            Some("=") => Ok(Modifier::Exact),
+            Some("^~") => Ok(Modifier::PriorityPrefix),
+            Some("~") => Ok(Modifier::Regex),
+            Some("~*") => Ok(Modifier::RegexCaseless),
+            Some(modifier) => Err(format!("invalid location modifier: {modifier}")),
+        }
+    }
+}
+
+impl fmt::Display for Modifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Modifier::Prefix => write!(f, "prefix"),
+            Modifier::Exact => write!(f, "="),
+            Modifier::PriorityPrefix => write!(f, "^~"),
+            Modifier::Regex => write!(f, "~"),
+            Modifier::RegexCaseless => write!(f, "~*"),
+        }
+    }
+}
+
+impl Modifier {
+    pub fn is(&self, other: Self) -> bool {
+        self.eq(&other)
+    }
+
+    pub fn is_regex(&self) -> bool {
+        matches!(self, Modifier::Regex | Modifier::RegexCaseless)
+    }
 }
 
 #[allow(dead_code)]
 impl Location {
-    pub(super) fn new(location: LocationBlock, server: &Server) -> Result<Self, String> {
+    /// `parent` is the server, or the enclosing location of a nested block
+    pub(super) fn new(location: LocationBlock, parent: &impl Config) -> Result<Self, String> {
+        let modifier = Modifier::try_from(location.modifier.as_deref())?;
+        let regex = match modifier.is_regex() {
+            true => match RegexBuilder::new(&location.path)
+                .case_insensitive(modifier.is(Modifier::RegexCaseless))
+                .build()
+            {
+                Ok(regex) => Some(regex),
+                Err(err) => return Err(format!("location ({}) : invalid regex: {err}", location.path)),
+            },
+            false => None,
+        };
+
         let mut new_location = Location {
             path: PathBuf::from(location.path),
-            exact_path: (location.modifier == Some("=".to_owned())),
+            modifier,
+            regex,
             error_pages: HashMap::new(),
             error_redirect: HashMap::new(),
             max_body_size: None,
             return_: None,
             root: None,
             upload_folder: None,
+            file_upload: None,
             alias: None,
             index: None,
             methods: None,
@@ -76,150 +185,37 @@ impl Location {
             auto_index: false,
             none_cgi: false,
             internal: false,
-            infos: HashMap::new(),
-            cgi: HashMap::new(),
+            charset: None,
+            cgi: location.cgi,
             server: None,
+            client_body_timeout: None,
+            send_timeout: None,
+            alias_base: None,
+            locations: Vec::new(),
+            routes: Routes::default(),
         };
 
-        for (name, infos) in location.directives {
-            match name.as_str() {
-                "root" => {
-                    if new_location.root.is_some() { return Err(format!("invalid field: root: root cannot be set with alias")); }
-  
-                    let root = parsing::extract_root(infos);
-                    match root {
-						Ok(path) => new_location.root = Some(path),
-                        Err(e) => {
-							return Err(format!("location ({}) : {}", new_location.path.display(), e))
-						},
-                    }
-                }
-                "alias" => {
-                    if new_location.root.is_some() {
-                        return Err(format!("invalid field: alias: alias cannot be set with root"));
-                    } else {
-                        new_location.alias = Some(parsing::extract_alias(infos)?)
-                    }
-                }
-                "upload_folder" => {
-                    new_location.upload_folder = Some(parsing::extract_upload_folder(infos)?)
-                }
-                "index" => {
-                    let index = parsing::extract_index(infos);
-                    match index {
-						Ok(index) => new_location.index = Some(index),
-                        Err(e) => {
-                            return Err(format!("location ({}) : {}", new_location.path.display(), e))
-                        },
-                    }
-                }
-                "auto_index" => {
-                    let auto_index = parsing::extract_auto_index(infos);
-                    match auto_index {
-						Ok(is_true) => new_location.auto_index = is_true,
-                        Err(e) => {
-                            return Err(format!("location ({}) : {}", new_location.path.display(), e))
-                        },
-                    }
-                }
-                "client_max_body_size" => {
-                    let max_body_size = parsing::extract_max_body_size(infos);
-                    match max_body_size {
-                        Err(e) => {
-                            return Err(format!(
-                                "location ({}) : {}",
-                                new_location.path.display(),
-                                e
-                            ))
-                        }
-                        Ok(max_size) => new_location.max_body_size = Some(max_size),
-                    }
-                }
-                "cgi" => {
-                    if infos.len() == 1 && infos[1] == "none" {
-                        new_location.none_cgi = true;
-                    } else {
-                        let (extension, path) = match parsing::extract_cgi(infos) {
-                            Err(e) => {
-                                return Err(format!(
-                                    "location ({}) : {}",
-                                    new_location.path.display(),
-                                    e
-                                ))
-                            }
-                            Ok(cgi) => cgi,
-                        };
-                        new_location.cgi.insert(extension, path);
-                    }
-                }
-                "allowed_methods" => {
-                    if infos.len() < 1 {
-                        return Err(format!(
-                            "location ({}) : invalid field: allowed_methods",
-                            new_location.path.display()
-                        ));
-                    }
-                    if new_location.methods.is_none() {
-                        new_location.methods = Some(Vec::new())
-                    }
-
-                    new_location.methods.as_mut().unwrap().append(
-                        &mut infos
-                            .iter()
-                            .map(|method| Method::from(&method[..]))
-                            .collect(),
-                    );
-                }
-                "redirect" => {
-                    if infos.len() != 1 {
-                        return Err(format!(
-                            "location ({}) : invalid field: redirect",
-                            new_location.path.display()
-                        ));
-                    }
-                    new_location.redirect = Some(infos[0].clone());
-                }
-                "return" => {
-                    new_location.return_ = match parsing::extract_return(infos) {
-                        Err(e) => {
-                            return Err(format!(
-                                "location ({}) : {}",
-                                new_location.path.display(),
-                                e
-                            ))
-                        }
-                        Ok(res) => Some(res),
-                    }
-                }
-                "internal" => {
-                    new_location.internal = true;
-                }
-                "error_page" => {
-                    let (pages, redirect) = parsing::extract_error_page(infos)?;
-                    let hash = &mut new_location.error_pages;
-                    if pages.is_some() {
-                        pages
-                            .unwrap()
-                            .iter()
-                            .map(|(code, url)| hash.insert(code.to_owned(), url.to_owned()))
-                            .last();
-                    }
-                    let hash = &mut new_location.error_redirect;
-                    if redirect.is_some() {
-                        redirect
-                            .unwrap()
-                            .iter()
-                            .map(|(code, url)| hash.insert(code.to_owned(), url.to_owned()))
-                            .last();
-                    }
-                }
-                _ => {
-                    new_location.infos.insert(name, infos);
-                }
+        for (name, values) in location.directives {
+            if let Err(err) = directives::apply(&mut new_location, Context::Location, &name, values) {
+                return Err(format!("location ({}) : {err}", new_location.path.display()));
+            }
+        }
+
+        if new_location.root.is_some() && new_location.alias.is_some() {
+            return Err(format!(
+                "location ({}) : invalid field: alias: alias cannot be set with root",
+                new_location.path.display()
+            ));
+        }
+
+        // 'redirect URL' is a shorthand for 'return 301 URL'
+        if new_location.return_.is_none() {
+            if let Some(url) = &new_location.redirect {
+                new_location.return_ = Some((301, Some(url.clone())));
             }
         }
 
-        new_location.complete_with_server_directives(server);
+        new_location.complete_with_parent_directives(parent);
 
         if new_location.none_cgi == true {
             new_location.cgi.clear();
@@ -227,6 +223,14 @@ impl Location {
 			Self::add_root_to_upload_folder(&mut new_location);	
 		}
 
+        // nested locations inherit from this one, once it is complete
+        for child in location.locations {
+            let child = Location::new(child, &new_location)?;
+            new_location.check_nested(&child)?;
+            new_location.locations.push(child);
+        }
+        new_location.routes = Routes::new(&new_location.locations)?;
+
         Ok(new_location)
     }
 
@@ -241,48 +245,88 @@ impl Location {
 		location.upload_folder = Some(upload_folder);
 	}
 
-    fn complete_with_server_directives(&mut self, server: &Server) {
-        self.internal = self.internal || server.internal();
-        self.auto_index = self.auto_index || server.auto_index();
+    fn complete_with_parent_directives(&mut self, parent: &impl Config) {
+        self.internal = self.internal || parent.internal();
+        self.auto_index = self.auto_index || parent.auto_index();
 
-        if self.root.is_none() && server.root().is_some() {
-            self.root = Some(server.root().unwrap().clone());
+        // an inherited alias keeps standing for the path of the location declaring it
+        if self.alias.is_none() && self.root.is_none() && parent.alias().is_some() {
+            self.alias = Some(parent.alias().unwrap().clone());
+            self.alias_base = Some(parent.alias_base().unwrap_or(parent.path()).clone());
+        }
+        if self.root.is_none() && parent.root().is_some() {
+            self.root = Some(parent.root().unwrap().clone());
         }
-        if self.upload_folder.is_none() && server.upload_folder().is_some() {
-            self.upload_folder = Some(server.upload_folder().unwrap().clone());
+        if self.methods.is_none() {
+            self.methods = parent.methods().cloned();
         }
-        if self.index.is_none() && server.index().is_some() {
-            self.index = Some(server.index().unwrap().clone());
+        if self.upload_folder.is_none() && parent.upload_folder().is_some() {
+            self.upload_folder = Some(parent.upload_folder().unwrap().clone());
         }
-        if self.max_body_size.is_none() && server.max_body_size().is_some() {
-            self.max_body_size = Some(server.max_body_size().unwrap().clone());
+        if self.charset.is_none() {
+            self.charset = parent.charset().cloned();
         }
-        if self.return_.is_none() && server.return_().is_some() {
-            self.return_ = Some(server.return_().unwrap().clone());
+        if self.file_upload.is_none() {
+            self.file_upload = parent.file_upload();
         }
-		if self.cgi.is_empty() && !server.cgi().is_empty() {
-            self.cgi = server.cgi().clone();
+        if self.index.is_none() && parent.index().is_some() {
+            self.index = Some(parent.index().unwrap().clone());
         }
-        if self.error_pages.is_empty() && !server.error_pages().is_empty() {
-            self.error_pages = server.error_pages().clone();
+        if self.max_body_size.is_none() {
+            self.max_body_size = parent.max_body_size().copied();
         }
-        if self.error_redirect.is_empty() && !server.error_redirect().is_empty() {
-            self.error_redirect = server.error_redirect().clone();
+		if self.cgi.is_empty() && !parent.cgi().is_empty() {
+            self.cgi = parent.cgi().clone();
+        }
+        if self.error_pages.is_empty() && !parent.error_pages().is_empty() {
+            self.error_pages = parent.error_pages().clone();
+        }
+        if self.error_redirect.is_empty() && !parent.error_redirect().is_empty() {
+            self.error_redirect = parent.error_redirect().clone();
+        }
+        if self.client_body_timeout.is_none() {
+            self.client_body_timeout = parent.client_body_timeout().copied();
+        }
+        if self.send_timeout.is_none() {
+            self.send_timeout = parent.send_timeout().copied();
         }
     }
 
-    pub fn add_server_ref(&mut self, serv: Arc<Server>) {
-        self.server = Some(serv);
+    /// Like nginx, a nested prefix location must stay inside its parent prefix
+    fn check_nested(&self, child: &Location) -> Result<(), String> {
+        if self.modifier.is(Modifier::Exact) {
+            return Err(format!(
+                "location ({}) : cannot be nested in exact location ({})",
+                child.path.display(),
+                self.path.display()
+            ));
+        }
+        if self.modifier.is_regex() || child.modifier.is_regex() {
+            return Ok(());
+        }
+
+        match child.path.to_string_lossy().starts_with(&*self.path.to_string_lossy()) {
+            true => Ok(()),
+            false => Err(format!(
+                "location ({}) : outside location ({})",
+                child.path.display(),
+                self.path.display()
+            )),
+        }
     }
 
-    pub fn find(&self, name: String) -> Option<&Vec<String>> {
-        self.infos.get(&name)
+    pub fn add_server_ref(&mut self, serv: &Weak<Server>) {
+        for child in self.locations.iter_mut() {
+            child.add_server_ref(serv);
+        }
+        self.server = Some(Weak::clone(serv));
     }
+
     pub fn path(&self) -> &PathBuf {
         &self.path
     }
 
-    pub fn exact_path(&self) -> bool {
-        self.exact_path
+    pub fn modifier(&self) -> Modifier {
+        self.modifier
     }
 }
@@ -1,13 +1,38 @@
+pub mod directives;
+pub mod globals;
 pub mod location;
+pub mod routing;
 pub mod server;
+pub mod server_names;
 pub mod traits;
 
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+pub const DEFAULT_CLIENT_HEADER_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_CLIENT_BODY_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(75);
+pub const DEFAULT_KEEPALIVE_REQUESTS: usize = 1000;
+pub const DEFAULT_CLIENT_HEADER_BUFFER_SIZE: usize = 1024;
+/// Address of a `listen port;` without address
+pub const DEFAULT_LISTEN_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
 /*------------------------------------------------------------*/
 /*-------------------[ Config Parsing ]-----------------------*/
 /*------------------------------------------------------------*/
 
 pub mod parsing {
-    use std::{collections::HashMap, path::PathBuf};
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        path::PathBuf,
+        time::Duration,
+    };
+
+    use super::server_names::Strict;
 
 
     pub fn extract_root(value: Vec<String>) -> Result<PathBuf, String> {
@@ -20,14 +45,15 @@ pub mod parsing {
         Ok(path)
     }
 
-    pub fn extract_alias(value: Vec<String>) -> Result<PathBuf, String> {
+    /// The alias of a regex location replaces the whole path, it may name a file
+    pub fn extract_alias(value: Vec<String>, regex: bool) -> Result<PathBuf, String> {
         if value.len() != 1 {
             return Err("invalid field: root".to_owned());
         }
 
         let path = PathBuf::from(&value[0]);
 
-        if path.to_string_lossy().chars().last() != Some('/') {
+        if !regex && !path.to_string_lossy().ends_with('/') {
             return Err(value[0].clone() + ": alias must ends with '/'");
         }
 
@@ -41,12 +67,8 @@ pub mod parsing {
 
         let path = PathBuf::from(&value[0]);
 
-        if path.exists() == false {
-            match std::fs::create_dir_all(&path) {
-                Ok(_) => (),
-                Err(err) => return Err(format!("failed to create upload folder: {err}")),
-            }
-        } else if path.is_dir() == false {
+        // a missing folder is created once the server runs, not while parsing
+        if path.exists() && !path.is_dir() {
             return Err(format!("invalid upload folder: {}", value[0]));
         }
 
@@ -58,12 +80,10 @@ pub mod parsing {
             return Err("invalid field: client_max_body_size".to_owned());
         }
 
-        let num = value[0].parse::<usize>();
-
-        return match num {
-            Ok(num) => Ok(num),
+        match parse_size(&value[0]) {
+            Ok(size) => Ok(size),
             Err(e) => Err(format!("invalid field: client_max_body_size: {e}")),
-        };
+        }
     }
 
     pub fn extract_error_page(
@@ -137,47 +157,77 @@ pub mod parsing {
         ))
     }
 
+    /// `return code [text|URL];` or `return URL;` (302)
     pub fn extract_return(value: Vec<String>) -> Result<(u16, Option<String>), String> {
         if value.len() < 1 || value.len() > 2 {
             return Err("invalid field: return".to_owned());
         }
 
+        if value.len() == 1 && is_url(&value[0]) {
+            return Ok((302, Some(value[0].clone())));
+        }
+
         let status_code = match value[0].parse::<u16>() {
-            Ok(num) => num,
+            Ok(num) if num <= 999 => num,
+            Ok(num) => return Err(format!("invalid field: return: invalid code {num}")),
             Err(e) => return Err(format!("invalid field: return: {e}")),
         };
 
-        let url = if value.len() == 2 {
-            match is_redirect_status_code(status_code) {
-                true => Some(value[1].clone()),
-                false => {
-                    println!(
-                        "'return' field: not redirect code, url ignored ({status_code} {})",
-                        value[1]
-                    );
-                    None
-                }
-            }
-        } else {
-            None
-        };
+        if is_redirect_status_code(status_code) && value.len() != 2 {
+            return Err(format!("invalid field: return: {status_code} needs an URL"));
+        }
+
+        Ok((status_code, value.get(1).cloned()))
+    }
 
-        Ok((status_code, url))
+    pub fn is_url(value: &str) -> bool {
+        value.starts_with("http://") || value.starts_with("https://") || value.starts_with("$scheme://")
     }
 
-    pub fn extract_listen(value: Vec<String>) -> Result<(Option<u16>, bool), String> {
+    /// `listen [address:]port [default | default_server]`, `*:port` for every
+    /// IPv4 address, `[::1]:port` for IPv6
+    pub fn extract_listen(value: Vec<String>) -> Result<(Option<IpAddr>, u16, bool), String> {
         if value.len() < 1 || value.len() > 2 {
             return Err("invalid field: port".to_owned());
         }
 
-        let default = value.len() == 2 && value[1] == "default";
+        let default = match value.get(1).map(String::as_str) {
+            None => false,
+            Some("default" | "default_server") => true,
+            Some(other) => return Err(format!("invalid field: listen: expected 'default', found {other}")),
+        };
 
-        let port = value[0].parse::<u16>();
+        let (address, port) = match value[0].rsplit_once(':') {
+            Some((address, port)) => (Some(extract_address(address)?), port),
+            None => (None, value[0].as_str()),
+        };
 
-        return match port {
-            Ok(num) => Ok((Some(num), default)),
+        match port.parse::<u16>() {
+            Ok(num) => Ok((address, num, default)),
             Err(err) => Err(format!("invalid field: port: {}", err)),
+        }
+    }
+
+    fn extract_address(address: &str) -> Result<IpAddr, String> {
+        let parsed = match address {
+            "*" => Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            "localhost" => Ok(super::DEFAULT_LISTEN_ADDRESS),
+            _ => match address.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+                Some(ipv6) => ipv6.parse::<Ipv6Addr>().map(IpAddr::V6),
+                None => address.parse::<Ipv4Addr>().map(IpAddr::V4),
+            },
         };
+
+        parsed.map_err(|_| format!("invalid field: listen: invalid address {address}"))
+    }
+
+    pub fn extract_strict_server_name(value: Vec<String>) -> Result<Strict, String> {
+        match value[0].as_str() {
+            "off" => Ok(Strict::Off),
+            "on" => Ok(Strict::Misdirected),
+            "close" => Ok(Strict::Close),
+            other => Err(format!("invalid field: strict_server_name: expected 'on', 'off' or 'close', found {other}")),
+        }
     }
 
     pub fn extract_index(value: Vec<String>) -> Result<String, String> {
@@ -188,16 +238,17 @@ pub mod parsing {
         Ok(value[0].clone())
     }
 
-    pub fn extract_auto_index(value: Vec<String>) -> Result<bool, String> {
+    /// `on` or `off` (auto_index, file_upload)
+    pub fn extract_flag(name: &str, value: Vec<String>) -> Result<bool, String> {
         if value.len() != 1 {
-            return Err("invalid field: auto_index".to_owned());
+            return Err(format!("invalid field: {name}"));
         }
 
         match &value[0][..] {
             "on" => Ok(true),
             "off" => Ok(false),
             _ => Err(format!(
-                "invalid field: auto_index: expected 'on' or 'off', found {}",
+                "invalid field: {name}: expected 'on' or 'off', found {}",
                 value[0]
             )),
         }
@@ -218,7 +269,233 @@ pub mod parsing {
         Ok((extension, path))
     }
 
+    pub fn extract_limit_conn(value: Vec<String>) -> Result<(String, usize), String> {
+        if value.len() != 2 {
+            return Err("invalid field: limit_conn: expected 'ip' or 'server' and a number".to_owned());
+        }
+
+        let key = match value[0].as_str() {
+            "ip" | "server" => value[0].clone(),
+            other => {
+                return Err(format!(
+                    "invalid field: limit_conn: expected 'ip' or 'server', found {other}"
+                ))
+            }
+        };
+
+        match value[1].parse::<usize>() {
+            Ok(0) => Err("invalid field: limit_conn: must be greater than 0".to_owned()),
+            Ok(num) => Ok((key, num)),
+            Err(e) => Err(format!("invalid field: limit_conn: {e}")),
+        }
+    }
+
+    pub fn extract_timeout(name: &str, value: Vec<String>) -> Result<Duration, String> {
+        if value.len() != 1 {
+            return Err(format!("invalid field: {name}"));
+        }
+
+        match parse_duration(&value[0]) {
+            Ok(duration) => Ok(duration),
+            Err(e) => Err(format!("invalid field: {name}: {e}")),
+        }
+    }
+
+    pub fn extract_count(name: &str, value: Vec<String>) -> Result<usize, String> {
+        if value.len() != 1 {
+            return Err(format!("invalid field: {name}"));
+        }
+
+        match value[0].parse::<usize>() {
+            Ok(0) => Err(format!("invalid field: {name}: must be greater than 0")),
+            Ok(num) => Ok(num),
+            Err(e) => Err(format!("invalid field: {name}: {e}")),
+        }
+    }
+
+    pub fn extract_size(name: &str, value: Vec<String>) -> Result<usize, String> {
+        if value.len() != 1 {
+            return Err(format!("invalid field: {name}"));
+        }
+
+        match parse_size(&value[0]) {
+            Ok(size) => Ok(size),
+            Err(e) => Err(format!("invalid field: {name}: {e}")),
+        }
+    }
+
+    pub fn extract_large_client_header_buffers(
+        value: Vec<String>,
+    ) -> Result<(usize, usize), String> {
+        if value.len() != 2 {
+            return Err("invalid field: large_client_header_buffers: expected number and size".to_owned());
+        }
+
+        let number = extract_count("large_client_header_buffers", vec![value[0].clone()])?;
+        let size = match parse_size(&value[1]) {
+            Ok(0) => return Err("invalid field: large_client_header_buffers: size must be greater than 0".to_owned()),
+            Ok(size) => size,
+            Err(e) => return Err(format!("invalid field: large_client_header_buffers: {e}")),
+        };
+
+        Ok((number, size))
+    }
+
+    /// `1024`, `8k`, `10M` or `1g` (case insensitive), in bytes
+    pub fn parse_size(value: &str) -> Result<usize, String> {
+        let (number, unit) = split_unit(value);
+
+        let factor: usize = match unit.to_ascii_lowercase().as_str() {
+            "" => 1,
+            "k" => 1 << 10,
+            "m" => 1 << 20,
+            "g" => 1 << 30,
+            _ => return Err(format!("invalid size \"{value}\": unknown unit \"{unit}\", expected k, m or g")),
+        };
+
+        match number.parse::<usize>() {
+            Ok(number) => number
+                .checked_mul(factor)
+                .ok_or(format!("invalid size \"{value}\": too large")),
+            Err(_) => Err(format!("invalid size \"{value}\": expected a number and an optional unit (k, m, g)")),
+        }
+    }
+
+    /// `30` (seconds), `500ms`, `30s`, `5m`, `1h`, `1d`, or a sum like `1m30s`
+    pub fn parse_duration(value: &str) -> Result<Duration, String> {
+        if let Ok(secs) = value.parse::<u64>() {
+            return Ok(Duration::from_secs(secs));
+        }
+
+        let invalid = || format!("invalid time \"{value}\": expected numbers followed by ms, s, m, h or d");
+        let mut rest = value;
+        let mut total = Duration::ZERO;
+
+        while !rest.is_empty() {
+            let (number, after) = split_unit(rest);
+            let unit_len = after.find(|c: char| c.is_ascii_digit()).unwrap_or(after.len());
+            let (unit, after) = after.split_at(unit_len);
+
+            let number = number.parse::<u64>().map_err(|_| invalid())?;
+            let millis: u64 = match unit {
+                "ms" => 1,
+                "s" => 1000,
+                "m" => 60 * 1000,
+                "h" => 60 * 60 * 1000,
+                "d" => 24 * 60 * 60 * 1000,
+                "" => return Err(invalid()),
+                _ => return Err(format!("invalid time \"{value}\": unknown unit \"{unit}\", expected ms, s, m, h or d")),
+            };
+
+            total = number
+                .checked_mul(millis)
+                .and_then(|millis| total.checked_add(Duration::from_millis(millis)))
+                .ok_or(format!("invalid time \"{value}\": too large"))?;
+            rest = after;
+        }
+
+        match value.is_empty() {
+            true => Err(invalid()),
+            false => Ok(total),
+        }
+    }
+
+    /// `duration` as `parse_duration` reads it back, `90s` as `1m30s`
+    pub fn format_duration(duration: Duration) -> String {
+        let millis = duration.as_millis();
+        if !millis.is_multiple_of(1000) {
+            return format!("{millis}ms");
+        }
+
+        let mut secs = duration.as_secs();
+        let mut out = String::new();
+        for (unit, length) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)] {
+            if secs >= length {
+                out.push_str(&format!("{}{unit}", secs / length));
+                secs %= length;
+            }
+        }
+
+        match out.is_empty() {
+            true => "0s".to_owned(),
+            false => out,
+        }
+    }
+
+    /// Leading digits and what follows them
+    fn split_unit(value: &str) -> (&str, &str) {
+        let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+        value.split_at(digits)
+    }
+
     pub fn is_redirect_status_code(code: u16) -> bool {
-        code == 301 || code == 302 || code == 303 || code == 307
+        code == 301 || code == 302 || code == 303 || code == 307 || code == 308
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::parsing::{extract_upload_folder, format_duration, parse_duration, parse_size};
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("8k"), Ok(8 << 10));
+        assert_eq!(parse_size("8K"), Ok(8 << 10));
+        assert_eq!(parse_size("10m"), Ok(10 << 20));
+        assert_eq!(parse_size("10M"), Ok(10 << 20));
+        assert_eq!(parse_size("1g"), Ok(1 << 30));
+        assert_eq!(parse_size("0"), Ok(0));
+    }
+
+    #[test]
+    fn malformed_sizes() {
+        for value in ["", "k", "8kb", "8t", "-1", "1.5m", " 8k", "8 k"] {
+            assert!(parse_size(value).is_err(), "{value:?}");
+        }
+        assert!(parse_size(&format!("{}g", usize::MAX)).unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(60 * 60)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(parse_duration("1m30s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1s500ms"), Ok(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn malformed_durations() {
+        for value in ["", "s", "5x", "5M", "1m30", "-1s", "1.5s", "5 s", "ms5"] {
+            assert!(parse_duration(value).is_err(), "{value:?}");
+        }
+        assert!(parse_duration(&format!("{}d", u64::MAX)).unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn upload_folder_is_checked_without_being_created() {
+        let dir = std::env::temp_dir().join(format!("webserv-upload-{}", std::process::id()));
+        let folder = dir.join("uploads").to_string_lossy().to_string();
+
+        assert!(extract_upload_folder(vec![folder.clone()]).is_ok());
+        assert!(!dir.exists());
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&folder, "").unwrap();
+        assert!(extract_upload_folder(vec![folder]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn durations_are_formatted_as_they_are_read() {
+        for value in ["0s", "500ms", "30s", "1m30s", "1h", "2d3h4m5s"] {
+            assert_eq!(format_duration(parse_duration(value).unwrap()), value);
+        }
     }
 }
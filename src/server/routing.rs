@@ -0,0 +1,223 @@
+/*---------------------------------------------------------------*/
+/*-------------------------[ ROUTING ]---------------------------*/
+/*---------------------------------------------------------------*/
+
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use super::{
+    location::{Location, Modifier},
+    traits::config::Config,
+};
+
+/// Location index of a server, built once when the configuration is loaded.
+/// Each entry is the position of the location in the server's list.
+#[derive(Clone, Debug, Default)]
+pub struct Routes {
+    exact: HashMap<String, usize>,
+    prefixes: PrefixTrie,
+    regexes: Vec<(Regex, usize)>,
+}
+
+impl Routes {
+    pub fn new(locations: &[Location]) -> Result<Self, String> {
+        let mut routes = Routes::default();
+
+        for (index, location) in locations.iter().enumerate() {
+            let path = location.path().to_string_lossy();
+            routes.add(index, location.modifier(), &path, location.regex())?;
+        }
+
+        Ok(routes)
+    }
+
+    pub fn add(
+        &mut self,
+        index: usize,
+        modifier: Modifier,
+        path: &str,
+        regex: Option<&Regex>,
+    ) -> Result<(), String> {
+        let duplicate = match modifier {
+            Modifier::Exact => self.exact.insert(path.to_owned(), index).is_some(),
+            Modifier::Prefix => !self.prefixes.insert(path.as_bytes(), index, false),
+            Modifier::PriorityPrefix => !self.prefixes.insert(path.as_bytes(), index, true),
+            Modifier::Regex | Modifier::RegexCaseless => match regex {
+                Some(regex) => {
+                    self.regexes.push((regex.clone(), index));
+                    false
+                }
+                None => return Err(format!("location ({path}) : regex not compiled")),
+            },
+        };
+
+        match duplicate {
+            true => Err(format!("location ({path}) : duplicate location")),
+            false => Ok(()),
+        }
+    }
+
+    /// Same order as nginx: exact match, then the longest prefix (final when
+    /// `^~`), then the first matching regex in file order, then the longest prefix.
+    pub fn find(&self, path: &str) -> Option<usize> {
+        if let Some(index) = self.exact.get(path) {
+            return Some(*index);
+        }
+
+        let longest = self.prefixes.longest_match(path.as_bytes());
+        if let Some((index, true)) = longest {
+            return Some(index);
+        }
+
+        self.regexes
+            .iter()
+            .find(|(regex, _)| regex.is_match(path))
+            .map(|(_, index)| *index)
+            .or(longest.map(|(index, _)| index))
+    }
+}
+
+/// Byte trie of the prefix locations: a lookup walks the path once and
+/// keeps the deepest location met, whatever the declaration order.
+#[derive(Clone, Debug, Default)]
+struct PrefixTrie {
+    children: HashMap<u8, PrefixTrie>,
+    location: Option<(usize, bool)>,
+}
+
+impl PrefixTrie {
+    /// false if `path` already has a location
+    fn insert(&mut self, path: &[u8], index: usize, priority: bool) -> bool {
+        let mut node = self;
+        for byte in path {
+            node = node.children.entry(*byte).or_default();
+        }
+
+        if node.location.is_some() {
+            return false;
+        }
+        node.location = Some((index, priority));
+        true
+    }
+
+    fn longest_match(&self, path: &[u8]) -> Option<(usize, bool)> {
+        let mut node = self;
+        let mut longest = node.location;
+
+        for byte in path {
+            node = match node.children.get(byte) {
+                Some(child) => child,
+                None => break,
+            };
+            if node.location.is_some() {
+                longest = node.location;
+            }
+        }
+
+        longest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::RegexBuilder;
+
+    use super::*;
+
+    fn routes(locations: &[(Modifier, &str)]) -> Routes {
+        let mut routes = Routes::default();
+
+        for (index, (modifier, path)) in locations.iter().enumerate() {
+            let regex = RegexBuilder::new(path)
+                .case_insensitive(modifier.is(Modifier::RegexCaseless))
+                .build()
+                .unwrap();
+            let regex = Some(&regex).filter(|_| modifier.is_regex());
+            routes.add(index, *modifier, path, regex).unwrap();
+        }
+
+        routes
+    }
+
+    #[test]
+    fn longest_prefix_is_not_lexicographic() {
+        let routes = routes(&[(Modifier::Prefix, "/a/zz"), (Modifier::Prefix, "/a/b/c")]);
+
+        assert_eq!(routes.find("/a/b/c/d"), Some(1));
+        assert_eq!(routes.find("/a/zz/d"), Some(0));
+        assert_eq!(routes.find("/a/b"), None);
+    }
+
+    #[test]
+    fn longest_prefix_ignores_declaration_order() {
+        let short_first = routes(&[(Modifier::Prefix, "/"), (Modifier::Prefix, "/api/")]);
+        let long_first = routes(&[(Modifier::Prefix, "/api/"), (Modifier::Prefix, "/")]);
+
+        assert_eq!(short_first.find("/api/users"), Some(1));
+        assert_eq!(long_first.find("/api/users"), Some(0));
+        assert_eq!(short_first.find("/apis"), Some(0));
+        assert_eq!(long_first.find("/apis"), Some(1));
+    }
+
+    #[test]
+    fn prefix_is_a_string_prefix() {
+        let routes = routes(&[(Modifier::Prefix, "/img"), (Modifier::Prefix, "/images/")]);
+
+        assert_eq!(routes.find("/imgs/a.png"), Some(0));
+        assert_eq!(routes.find("/images/a.png"), Some(1));
+        assert_eq!(routes.find("/image"), None);
+    }
+
+    #[test]
+    fn exact_match_wins_and_only_matches_itself() {
+        let routes = routes(&[(Modifier::Prefix, "/"), (Modifier::Exact, "/"), (Modifier::Regex, ".*")]);
+
+        assert_eq!(routes.find("/"), Some(1));
+        assert_eq!(routes.find("/index.html"), Some(2));
+    }
+
+    #[test]
+    fn regex_beats_plain_prefix_in_file_order() {
+        let routes = routes(&[
+            (Modifier::Prefix, "/img/"),
+            (Modifier::RegexCaseless, r"\.PNG$"),
+            (Modifier::Regex, r"^/img/.+\.png$"),
+        ]);
+
+        assert_eq!(routes.find("/img/cat.png"), Some(1));
+        assert_eq!(routes.find("/img/cat.gif"), Some(0));
+        assert_eq!(routes.find("/other.png"), Some(1));
+    }
+
+    #[test]
+    fn priority_prefix_stops_regex_search() {
+        let routes = routes(&[
+            (Modifier::PriorityPrefix, "/static/"),
+            (Modifier::Prefix, "/static/js/"),
+            (Modifier::Regex, r"\.js$"),
+        ]);
+
+        assert_eq!(routes.find("/static/a.js"), Some(0));
+        // a longer plain prefix takes over, regexes apply again
+        assert_eq!(routes.find("/static/js/a.js"), Some(2));
+        assert_eq!(routes.find("/static/js/a.css"), Some(1));
+    }
+
+    #[test]
+    fn case_sensitivity_of_regexes() {
+        let routes = routes(&[(Modifier::Regex, r"\.php$"), (Modifier::RegexCaseless, r"\.py$")]);
+
+        assert_eq!(routes.find("/a.php"), Some(0));
+        assert_eq!(routes.find("/a.PHP"), None);
+        assert_eq!(routes.find("/a.PY"), Some(1));
+    }
+
+    #[test]
+    fn duplicate_prefix_is_rejected() {
+        let mut routes = routes(&[(Modifier::Prefix, "/a")]);
+
+        assert!(routes.add(1, Modifier::PriorityPrefix, "/a", None).is_err());
+        assert!(routes.add(1, Modifier::Exact, "/a", None).is_ok());
+    }
+}
@@ -1,18 +1,26 @@
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, path::PathBuf, sync::Arc, time::Duration};
 
-use crate::{request::Method, LocationBlock, ServerBlock};
+use regex::Regex;
+
+use crate::{
+    listener::limits::ConnLimiter,
+    request::{HeaderLimits, Method},
+    LocationBlock, ServerBlock,
+};
 
 use super::{
+    directives::{self, Context},
     location::Location,
-    parsing,
-    traits::{config::Config, handler::Handler},
+    routing::Routes, server_names::{Strict, VirtualHosts}, DEFAULT_CLIENT_HEADER_BUFFER_SIZE, DEFAULT_CLIENT_HEADER_TIMEOUT,
+    DEFAULT_KEEPALIVE_REQUESTS, DEFAULT_KEEPALIVE_TIMEOUT, DEFAULT_LISTEN_ADDRESS,
+    traits::{config::Config, handler::Handler, settings::{Applied, Settings}},
 };
 
 /*---------------------------------------------------------------*/
 /*-------------------------[ SERVER ]----------------------------*/
 /*---------------------------------------------------------------*/
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 #[allow(dead_code)]
 pub struct Server {
     internal: bool,
@@ -24,15 +32,29 @@ pub struct Server {
     max_body_size: Option<usize>,
     root: Option<PathBuf>,
     upload_folder: Option<PathBuf>,
+    file_upload: Option<bool>,
     index: Option<String>,
     return_: Option<(u16, Option<String>)>,
     name: Option<Vec<String>>,
     methods: Option<Vec<Method>>,
     error_pages: HashMap<u16, String>,
     error_redirect: HashMap<u16, (Option<u16>, String)>,
-    infos: HashMap<String, Vec<String>>,
+    charset: Option<String>,
     cgi: HashMap<String, PathBuf>,
-    locations: HashMap<PathBuf, Location>,
+    locations: Vec<Location>,
+    routes: Routes,
+    limit_conn_ip: Option<usize>,
+    limit_conn_server: Option<usize>,
+    limiter: Arc<ConnLimiter>,
+    client_header_timeout: Option<Duration>,
+    client_body_timeout: Option<Duration>,
+    send_timeout: Option<Duration>,
+    keepalive_timeout: Option<Duration>,
+    keepalive_requests: Option<usize>,
+    client_header_buffer_size: Option<usize>,
+    large_client_header_buffers: Option<(usize, usize)>,
+    client_max_headers: Option<usize>,
+    strict_server_name: Strict,
 }
 
 impl Handler for Server {}
@@ -44,6 +66,7 @@ impl Config for Server {
     fn is_location(&self) -> bool /*------------------------------*/ { false }
     fn port(&self) -> Option<&u16> /*-----------------------------*/ { self.port.as_ref() }
     fn index(&self) -> Option<&String> /*-------------------------*/ { self.index.as_ref() }
+    fn charset(&self) -> Option<&String> /*-----------------------*/ { self.charset.as_ref() }
     fn root(&self) -> Option<&PathBuf> /*-------------------------*/ { self.root.as_ref() }
     fn alias(&self) -> Option<&PathBuf> /*------------------------*/ { None }
     fn name(&self) -> Option<&Vec<String>> /*---------------------*/ { self.name.as_ref() }
@@ -51,10 +74,57 @@ impl Config for Server {
     fn max_body_size(&self) -> Option<&usize> /*------------------*/ { self.max_body_size.as_ref() }
     fn cgi(&self) -> &HashMap<String, PathBuf> /*-----------------*/ { &self.cgi }
     fn upload_folder(&self) -> Option<&PathBuf> /*----------------*/ { self.upload_folder.as_ref() }
+    fn file_upload(&self) -> Option<bool> /*----------------------*/ { self.file_upload }
     fn error_pages(&self) -> &HashMap<u16, String> /*-------------*/ { &self.error_pages }
     fn return_(&self) -> Option<&(u16, Option<String>)> /*--------*/ { self.return_.as_ref() }
-    fn locations(&self) -> Option<&HashMap<PathBuf, Location>> /*-*/ { Some(&self.locations) }
+    fn locations(&self) -> Option<&Vec<Location>> /*-------------*/ { Some(&self.locations) }
     fn error_redirect(&self) -> &HashMap<u16, (Option<u16>, String)> { &self.error_redirect }
+    fn client_body_timeout(&self) -> Option<&Duration> /*---------*/ { self.client_body_timeout.as_ref() }
+    fn send_timeout(&self) -> Option<&Duration> /*----------------*/ { self.send_timeout.as_ref() }
+    fn server(&self) -> Option<Arc<Server>> /*--------------------*/ { None }
+    fn regex(&self) -> Option<&Regex> /*--------------------------*/ { None }
+    fn routes(&self) -> Option<&Routes> /*------------------------*/ { Some(&self.routes) }
+    fn alias_base(&self) -> Option<&PathBuf> /*-------------------*/ { None }
+}
+
+impl Settings for Server {
+    fn set_root(&mut self, root: PathBuf) -> Applied /*-----------------------*/ { self.root = Some(root); Ok(()) }
+    fn set_upload_folder(&mut self, folder: PathBuf) -> Applied /*------------*/ { self.upload_folder = Some(folder); Ok(()) }
+    fn set_file_upload(&mut self, on: bool) -> Applied /*---------------------*/ { self.file_upload = Some(on); Ok(()) }
+    fn set_index(&mut self, index: String) -> Applied /*----------------------*/ { self.index = Some(index); Ok(()) }
+    fn set_auto_index(&mut self, on: bool) -> Applied /*----------------------*/ { self.auto_index = on; Ok(()) }
+    fn set_max_body_size(&mut self, size: usize) -> Applied /*----------------*/ { self.max_body_size = Some(size); Ok(()) }
+    fn set_charset(&mut self, charset: String) -> Applied /*------------------*/ { self.charset = Some(charset); Ok(()) }
+    fn add_cgi(&mut self, extension: String, path: PathBuf) -> Applied /*-----*/ { self.cgi.insert(extension, path); Ok(()) }
+    fn add_methods(&mut self, methods: Vec<Method>) -> Applied /*-------------*/ { self.methods.get_or_insert_with(Vec::new).extend(methods); Ok(()) }
+    fn set_return(&mut self, return_: (u16, Option<String>)) -> Applied /*----*/ { self.return_ = Some(return_); Ok(()) }
+    fn add_error_pages(&mut self, pages: HashMap<u16, String>) -> Applied /*--*/ { self.error_pages.extend(pages); Ok(()) }
+    fn add_error_redirects(&mut self, redirects: HashMap<u16, (Option<u16>, String)>) -> Applied { self.error_redirect.extend(redirects); Ok(()) }
+    fn set_internal(&mut self) -> Applied /*----------------------------------*/ { self.internal = true; Ok(()) }
+    fn set_client_body_timeout(&mut self, timeout: Duration) -> Applied /*----*/ { self.client_body_timeout = Some(timeout); Ok(()) }
+    fn set_send_timeout(&mut self, timeout: Duration) -> Applied /*-----------*/ { self.send_timeout = Some(timeout); Ok(()) }
+    fn add_server_names(&mut self, names: Vec<String>) -> Applied /*----------*/ { self.name.get_or_insert_with(Vec::new).extend(names); Ok(()) }
+    fn set_strict_server_name(&mut self, strict: Strict) -> Applied /*--------*/ { self.strict_server_name = strict; Ok(()) }
+    fn set_client_header_timeout(&mut self, timeout: Duration) -> Applied /*--*/ { self.client_header_timeout = Some(timeout); Ok(()) }
+    fn set_keepalive_timeout(&mut self, timeout: Duration) -> Applied /*------*/ { self.keepalive_timeout = Some(timeout); Ok(()) }
+    fn set_keepalive_requests(&mut self, max: usize) -> Applied /*------------*/ { self.keepalive_requests = Some(max); Ok(()) }
+    fn set_client_header_buffer_size(&mut self, size: usize) -> Applied /*----*/ { self.client_header_buffer_size = Some(size); Ok(()) }
+    fn set_large_client_header_buffers(&mut self, buffers: (usize, usize)) -> Applied { self.large_client_header_buffers = Some(buffers); Ok(()) }
+    fn set_client_max_headers(&mut self, max: usize) -> Applied /*------------*/ { self.client_max_headers = Some(max); Ok(()) }
+
+    fn set_listen(&mut self, address: Option<IpAddr>, port: u16, default: bool) -> Applied {
+        let address = address.unwrap_or(DEFAULT_LISTEN_ADDRESS);
+        (self.port, self.socket, self.default) = (Some(port), Some(SocketAddr::new(address, port)), default);
+        Ok(())
+    }
+
+    fn set_limit_conn(&mut self, key: String, max: usize) -> Applied {
+        match key.as_str() {
+            "ip" => self.limit_conn_ip = Some(max),
+            _ => self.limit_conn_server = Some(max),
+        }
+        Ok(())
+    }
 }
 
 /*---------------------------------------------------------------*/
@@ -69,6 +139,7 @@ impl Server {
             socket: None,
             root: None,
             upload_folder: None,
+            file_upload: None,
             path: PathBuf::from("/"),
             max_body_size: None,
             index: None,
@@ -77,22 +148,37 @@ impl Server {
             auto_index: false,
             error_pages: HashMap::new(),
             error_redirect: HashMap::new(),
-            infos: HashMap::new(),
-            locations: HashMap::new(),
+            charset: None,
+            locations: Vec::new(),
+            routes: Routes::default(),
             cgi: config.cgi,
             default: false,
             name: None,
             internal: false,
+            limit_conn_ip: None,
+            limit_conn_server: None,
+            limiter: Arc::default(),
+            client_header_timeout: None,
+            client_body_timeout: None,
+            send_timeout: None,
+            keepalive_timeout: None,
+            keepalive_requests: None,
+            client_header_buffer_size: None,
+            large_client_header_buffers: None,
+            client_max_headers: None,
+            strict_server_name: Strict::Off,
         };
 
-        for directive in config.directives {
-            serv.add_directive(directive.0, directive.1)?;
+        for (name, values) in config.directives {
+            directives::apply(&mut serv, Context::Server, &name, values)?;
         }
 
         for location in config.locations {
-            serv.add_location(location.1)?;
+            serv.add_location(location)?;
         }
 
+        serv.limiter = Arc::new(ConnLimiter::new(serv.limit_conn_ip, serv.limit_conn_server));
+
         Ok(serv)
     }
 }
@@ -112,146 +198,91 @@ impl Server {
         Ok(servers)
     }
 
-    pub fn parse_servers(servers: Vec<Self>) -> Result<HashMap<u16, Vec<Server>>, String> {
-        let mut map: HashMap<u16, Vec<Self>> = HashMap::new();
+    /// Servers grouped by listen address, each address with its own default server
+    pub fn parse_servers(servers: Vec<Self>) -> Result<HashMap<SocketAddr, VirtualHosts>, String> {
+        let mut map: HashMap<SocketAddr, Vec<Arc<Self>>> = HashMap::new();
 
-        let mut i = 1;
-        for serv in servers {
-            let port = match serv.parse() {
-                Ok(port) => port,
+        for (i, mut serv) in (1..).zip(servers) {
+            if let Err(err) = serv.compile_routes() {
+                return Err(format!("server {i}: {err}"));
+            }
+
+            let socket = match serv.parse() {
+                Ok(socket) => socket,
                 Err(err) => return Err(format!("server {i}: {err}")),
             };
 
-            if let Some(vec) = map.get_mut(&port) {
+            let serv = serv.into_shared();
+            if let Some(vec) = map.get_mut(&socket) {
                 vec.push(serv);
             } else {
-                map.insert(port, vec![serv]);
+                map.insert(socket, vec![serv]);
             }
-            i += 1;
         }
 
-        for (port, vec) in &map {
-            if vec.iter().filter(|serv| serv.default == true).count() > 1 {
-                return Err(format!("port {port}: multiple default servers"));
+        for (socket, vec) in &map {
+            if vec.iter().filter(|serv| serv.default).count() > 1 {
+                return Err(format!("{socket}: multiple default servers"));
             }
         }
 
-        Ok(map)
-    }
-
-    fn parse(&self) -> Result<u16, String> {
-        if self.port.is_none() {
-            return Err(String::from("no listen"));
+        let mut hosts = HashMap::new();
+        for (socket, vec) in map {
+            match VirtualHosts::new(vec) {
+                Ok(socket_hosts) => hosts.insert(socket, socket_hosts),
+                Err(err) => return Err(format!("{socket}: {err}")),
+            };
         }
 
-        // others checks ? (only one default by port)
-
-        Ok(self.port.unwrap())
+        Ok(hosts)
     }
 
-    fn add_directive(&mut self, name: String, infos: Vec<String>) -> Result<(), String> {
-        match name.as_str() {
-            "root" => {
-                if self.root.is_some() {
-                    println!("Warning: root: duplicated value")
-                };
-                self.root = Some(parsing::extract_root(infos)?)
-            }
-            "alias" => {
-                return Err(format!(
-                    "invalid field: alias: alias can only be set in locations"
-                ));
-            }
-            "upload_folder" => self.upload_folder = Some(parsing::extract_upload_folder(infos)?),
-            "listen" => {
-                (self.port, self.default) = parsing::extract_listen(infos)?;
-            }
-            "server_name" | "server_names" => {
-                if infos.len() < 1 {
-                    return Err("invalid field: server_name".to_owned());
-                } else {
-                    if self.name.is_none() {
-                        self.name = Some(Vec::new())
-                    }
-
-                    self.name.as_mut().unwrap().append(&mut infos.clone());
+    /// Creates the missing upload folders of the servers about to run: the
+    /// configuration itself is parsed without touching the disk (`-t`, explain)
+    pub fn create_upload_folders(servers: &HashMap<SocketAddr, VirtualHosts>) -> Result<(), String> {
+        fn create(config: &impl Config) -> Result<(), String> {
+            if let Some(folder) = config.upload_folder() {
+                if let Err(err) = std::fs::create_dir_all(folder) {
+                    return Err(format!("failed to create upload folder {}: {err}", folder.display()));
                 }
             }
-            "index" => {
-                self.index = Some(parsing::extract_index(infos)?);
-            }
-            "auto_index" => {
-                self.auto_index = parsing::extract_auto_index(infos)?;
-            }
-            "client_max_body_size" => {
-                self.max_body_size = Some(parsing::extract_max_body_size(infos)?);
-            }
-            "cgi" => {
-                let (extension, path) = parsing::extract_cgi(infos)?;
-                self.cgi.insert(extension, path);
+            for location in config.locations().into_iter().flatten() {
+                create(location)?;
             }
-            "allowed_methods" => {
-                if infos.len() < 1 {
-                    return Err("invalid field: allowed_methods".to_owned());
-                }
+            Ok(())
+        }
 
-                let methods: Vec<Result<Method, String>> = infos
-                    .clone()
-                    .iter()
-                    .map(|method| Method::try_from_str(&method[..]))
-                    .collect();
+        servers.values().flat_map(|hosts| hosts.servers()).try_for_each(|serv| create(&**serv))
+    }
 
-                if methods.iter().any(|res| res.is_err()) {
-                    return Err(format!("invalid field: allowed_methods: unknown method"));
-                }
+    fn compile_routes(&mut self) -> Result<(), String> {
+        self.routes = Routes::new(&self.locations)?;
 
-                if self.methods.is_none() {
-                    self.methods = Some(Vec::new())
-                }
+        Ok(())
+    }
 
-                let mut method = methods
-                    .iter()
-                    .map(|method| method.as_ref().unwrap().to_owned())
-                    .collect::<Vec<Method>>();
-                self.methods.as_mut().unwrap().append(&mut method);
-            }
-            "return" => {
-                self.return_ = Some(parsing::extract_return(infos)?);
-            }
-            "error_page" => {
-                let (pages, redirect) = parsing::extract_error_page(infos)?;
-                let hash = &mut self.error_pages;
-                if pages.is_some() {
-                    pages
-                        .unwrap()
-                        .iter()
-                        .map(|(code, url)| hash.insert(code.to_owned(), url.to_owned()))
-                        .last();
-                }
-                let hash = &mut self.error_redirect;
-                if redirect.is_some() {
-                    redirect
-                        .unwrap()
-                        .iter()
-                        .map(|(code, url)| hash.insert(code.to_owned(), url.to_owned()))
-                        .last();
-                }
-            }
-            "internal" => {
-                self.internal = true;
-            }
-            _ => {
-                self.infos.insert(name, infos);
+    /// The server as shared by every connection, its locations pointing back
+    /// to it: they need its routes to run subrequests (error pages)
+    fn into_shared(mut self) -> Arc<Self> {
+        Arc::new_cyclic(|server| {
+            for location in self.locations.iter_mut() {
+                location.add_server_ref(server);
             }
+            self
+        })
+    }
+
+    fn parse(&self) -> Result<SocketAddr, String> {
+        match self.socket {
+            Some(socket) => Ok(socket),
+            None => Err(String::from("no listen")),
         }
-        Ok(())
     }
 
     fn add_location(&mut self, location: LocationBlock) -> Result<(), String> {
-        let new_location = Location::new(location, &self)?;
+        let new_location = Location::new(location, &*self)?;
 
-        self.locations
-            .insert(new_location.path().clone(), new_location);
+        self.locations.push(new_location);
 
         Ok(())
     }
@@ -267,9 +298,52 @@ impl Server {
         self.default
     }
 
-    pub fn get(&self, info: String) -> Option<String> {
-        Some(self.infos.get(&info)?.join(" "))
+    pub fn strict_server_name(&self) -> Strict {
+        self.strict_server_name
+    }
+
+    pub fn limiter(&self) -> &Arc<ConnLimiter> {
+        &self.limiter
+    }
+
+    /// `limit_conn ip` and `limit_conn server`
+    pub fn limit_conn(&self) -> (Option<usize>, Option<usize>) {
+        (self.limit_conn_ip, self.limit_conn_server)
+    }
+
+    pub fn client_header_timeout(&self) -> Duration {
+        self.client_header_timeout.unwrap_or(DEFAULT_CLIENT_HEADER_TIMEOUT)
     }
+
+    pub fn keepalive_timeout(&self) -> Duration {
+        self.keepalive_timeout.unwrap_or(DEFAULT_KEEPALIVE_TIMEOUT)
+    }
+
+    pub fn keepalive_requests(&self) -> usize {
+        self.keepalive_requests.unwrap_or(DEFAULT_KEEPALIVE_REQUESTS)
+    }
+
+    pub fn client_header_buffer_size(&self) -> usize {
+        self.client_header_buffer_size.unwrap_or(DEFAULT_CLIENT_HEADER_BUFFER_SIZE)
+    }
+
+    pub fn header_limits(&self) -> HeaderLimits {
+        let mut limits = HeaderLimits {
+            buffer_size: self.client_header_buffer_size(),
+            ..Default::default()
+        };
+
+        if let Some((number, size)) = self.large_client_header_buffers {
+            limits.large_count = number;
+            limits.line_size = size;
+        }
+        if let Some(max_count) = self.client_max_headers {
+            limits.max_count = max_count;
+        }
+
+        limits
+    }
+
 }
 
 /*-------------------------------------------------------------------------------------------------------*/
@@ -0,0 +1,285 @@
+/*---------------------------------------------------------------*/
+/*-----------------------[ SERVER NAMES ]------------------------*/
+/*---------------------------------------------------------------*/
+
+use std::{cmp::Reverse, collections::HashMap, sync::Arc};
+
+use regex::{Regex, RegexBuilder};
+
+use super::{server::Server, traits::config::Config};
+
+/// Named groups of the regex server_name that matched, as (name, value)
+pub type Captures = Vec<(String, String)>;
+
+/// `strict_server_name`: answer of the default server to a Host no
+/// server_name of the address matches
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strict {
+    /// served by the default server
+    #[default]
+    Off,
+    /// 421 Misdirected Request
+    Misdirected,
+    /// connection closed without response, like nginx `return 444`
+    Close,
+}
+
+/// server_name index of the servers listening on a port, built once when the
+/// configuration is loaded. Each entry is the position of the server in the port's list.
+#[derive(Clone, Debug, Default)]
+pub struct ServerNames {
+    exact: HashMap<String, usize>,
+    /// `*.example.com` and `.example.com` as `.example.com` (the latter also
+    /// matching `example.com`), longest first
+    leading: Vec<(String, bool, usize)>,
+    /// `www.example.*` as `www.example.`, longest first
+    trailing: Vec<(String, usize)>,
+    /// `~pattern`, in file order
+    regexes: Vec<(Regex, usize)>,
+}
+
+impl ServerNames {
+    pub fn new(servers: &[Arc<Server>]) -> Result<Self, String> {
+        let mut names = ServerNames::default();
+
+        for (index, server) in servers.iter().enumerate() {
+            for name in server.name().into_iter().flatten() {
+                names.add(index, name)?;
+            }
+        }
+
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// Longest wildcards first. Stable: on a tie, the server declared first wins
+    fn sort(&mut self) {
+        self.leading.sort_by_key(|(suffix, _, _)| Reverse(suffix.len()));
+        self.trailing.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+    }
+
+    /// A name given to several servers stays with the first one
+    pub fn add(&mut self, index: usize, name: &str) -> Result<(), String> {
+        if let Some(pattern) = name.strip_prefix('~') {
+            let regex = match RegexBuilder::new(pattern).case_insensitive(true).build() {
+                Ok(regex) => regex,
+                Err(err) => return Err(format!("server_name \"{name}\": {err}")),
+            };
+            self.regexes.push((regex, index));
+            return Ok(());
+        }
+
+        let name = name.to_ascii_lowercase();
+        let leading = name.strip_prefix('*').filter(|suffix| suffix.starts_with('.'));
+        let trailing = name.strip_suffix('*').filter(|prefix| prefix.ends_with('.'));
+
+        match (leading, trailing) {
+            (Some(suffix), _) if !suffix.contains('*') => self.leading.push((suffix.to_owned(), false, index)),
+            (_, Some(prefix)) if !prefix.contains('*') => self.trailing.push((prefix.to_owned(), index)),
+            _ if name.contains('*') => {
+                return Err(format!("server_name \"{name}\": invalid wildcard, expected \"*.name\" or \"name.*\""));
+            }
+            _ if name.starts_with('.') => self.leading.push((name, true, index)),
+            _ => {
+                self.exact.entry(name).or_insert(index);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Same order as nginx: exact name, longest leading wildcard, longest
+    /// trailing wildcard, then the first matching regex in file order.
+    /// `host` is lowercase and without port (`Request::hostname`), empty when
+    /// the request has no Host (matched by `server_name ""`).
+    pub fn find(&self, host: &str) -> Option<(usize, Captures)> {
+        if let Some(index) = self.exact.get(host) {
+            return Some((*index, Vec::new()));
+        }
+
+        let leading = self.leading.iter().find(|(suffix, bare, _)| {
+            (host.len() > suffix.len() && host.ends_with(suffix.as_str())) || (*bare && host == &suffix[1..])
+        });
+        if let Some((_, _, index)) = leading {
+            return Some((*index, Vec::new()));
+        }
+
+        let trailing = self
+            .trailing
+            .iter()
+            .find(|(prefix, _)| host.len() > prefix.len() && host.starts_with(prefix.as_str()));
+        if let Some((_, index)) = trailing {
+            return Some((*index, Vec::new()));
+        }
+
+        self.regexes.iter().find_map(|(regex, index)| {
+            let captures = regex.captures(host)?;
+            let named = regex
+                .capture_names()
+                .flatten()
+                .filter_map(|name| Some((name.to_owned(), captures.name(name)?.as_str().to_owned())))
+                .collect();
+            Some((*index, named))
+        })
+    }
+}
+
+/// The servers listening on a port and their server_name index, swapped
+/// together when the configuration is reloaded
+#[derive(Clone, Debug, Default)]
+pub struct VirtualHosts {
+    servers: Vec<Arc<Server>>,
+    names: ServerNames,
+}
+
+impl VirtualHosts {
+    pub fn new(servers: Vec<Arc<Server>>) -> Result<Self, String> {
+        let names = ServerNames::new(&servers)?;
+
+        Ok(VirtualHosts { servers, names })
+    }
+
+    pub fn servers(&self) -> &[Arc<Server>] {
+        &self.servers
+    }
+
+    /// The server marked `default`, else the first one
+    pub fn default_server(&self) -> &Server {
+        match self.servers.iter().find(|serv| serv.is_default()) {
+            Some(serv) => serv,
+            None => self.servers.first().unwrap(),
+        }
+    }
+
+    /// The server named `host`, else the default server without captures
+    /// (see its `strict_server_name`)
+    pub fn find(&self, host: Option<&str>) -> (&Server, Option<Captures>) {
+        match self.names.find(host.unwrap_or_default()) {
+            Some((index, captures)) => (&self.servers[index], Some(captures)),
+            None => (self.default_server(), None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::ServerBlock;
+
+    /// Each server as its directives, `name value ...`
+    fn hosts(servers: &[&[&str]]) -> HashMap<SocketAddr, VirtualHosts> {
+        let blocks = servers
+            .iter()
+            .map(|directives| {
+                let directives = directives
+                    .iter()
+                    .map(|line| {
+                        let mut words = line.split_whitespace().map(str::to_owned);
+                        (words.next().unwrap(), words.collect())
+                    })
+                    .collect();
+                ServerBlock { directives, ..Default::default() }
+            })
+            .collect();
+
+        Server::parse_servers(Server::init_servers(blocks).unwrap()).unwrap()
+    }
+
+    fn names(names: &[&str]) -> ServerNames {
+        let mut table = ServerNames::default();
+        for (index, name) in names.iter().enumerate() {
+            table.add(index, name).unwrap();
+        }
+        table.sort();
+        table
+    }
+
+    fn find(table: &ServerNames, host: &str) -> Option<usize> {
+        table.find(host).map(|(index, _)| index)
+    }
+
+    #[test]
+    fn resolution_order() {
+        let table = names(&["~^www\\.", "www.example.*", "*.example.com", "www.example.com"]);
+
+        assert_eq!(find(&table, "www.example.com"), Some(3));
+        assert_eq!(find(&table, "api.example.com"), Some(2));
+        assert_eq!(find(&table, "www.example.org"), Some(1));
+        assert_eq!(find(&table, "www.other.org"), Some(0));
+        assert_eq!(find(&table, "other.org"), None);
+    }
+
+    #[test]
+    fn longest_wildcard_wins() {
+        let table = names(&["*.example.com", "*.api.example.com", "mail.*", "mail.example.*"]);
+
+        assert_eq!(find(&table, "v1.api.example.com"), Some(1));
+        assert_eq!(find(&table, "www.example.com"), Some(0));
+        assert_eq!(find(&table, "mail.example.org"), Some(3));
+        assert_eq!(find(&table, "mail.other.org"), Some(2));
+    }
+
+    #[test]
+    fn dot_wildcard_also_matches_the_bare_name() {
+        let table = names(&[".example.com", "*.other.com"]);
+
+        assert_eq!(find(&table, "example.com"), Some(0));
+        assert_eq!(find(&table, "a.b.example.com"), Some(0));
+        assert_eq!(find(&table, "other.com"), None);
+    }
+
+    #[test]
+    fn regex_named_captures() {
+        let table = names(&["~^(?<sub>[a-z]+)\\.(?<domain>example\\.com)$"]);
+
+        let (_, captures) = table.find("shop.example.com").unwrap();
+        assert_eq!(
+            captures,
+            vec![("sub".to_owned(), "shop".to_owned()), ("domain".to_owned(), "example.com".to_owned())]
+        );
+    }
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let table = names(&["Example.COM", "~^API\\."]);
+
+        assert_eq!(find(&table, "example.com"), Some(0));
+        assert_eq!(find(&table, "api.example.org"), Some(1));
+    }
+
+    #[test]
+    fn invalid_wildcards_are_rejected() {
+        let mut table = ServerNames::default();
+
+        assert!(table.add(0, "www.*.com").is_err());
+        assert!(table.add(0, "*example.com").is_err());
+        assert!(table.add(0, "~(").is_err());
+    }
+
+    #[test]
+    fn default_server_of_each_address() {
+        let hosts = hosts(&[
+            &["listen 127.0.0.1:8080", "server_name a.fr"],
+            &["listen 127.0.0.1:8080 default_server", "server_name b.fr"],
+            &["listen 127.0.0.2:8080", "server_name c.fr"],
+        ]);
+        let first: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let second: SocketAddr = "127.0.0.2:8080".parse().unwrap();
+
+        // no Host, unknown Host: the default server, not the first one
+        for host in [None, Some("unknown.fr")] {
+            let (server, captures) = hosts[&first].find(host);
+            assert_eq!(server.name(), Some(&vec!["b.fr".to_owned()]));
+            assert!(captures.is_none());
+        }
+        let (server, captures) = hosts[&first].find(Some("a.fr"));
+        assert_eq!(server.name(), Some(&vec!["a.fr".to_owned()]));
+        assert!(captures.is_some());
+
+        let (server, _) = hosts[&second].find(Some("b.fr"));
+        assert_eq!(server.name(), Some(&vec!["c.fr".to_owned()]));
+    }
+}
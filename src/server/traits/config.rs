@@ -1,9 +1,17 @@
-use std::{borrow::Cow, collections::HashMap, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use regex::Regex;
 
 use crate::{
-    request::{Method, Request},
+    request::{extension_to_content_type, Method, Request},
     response::response::ResponseCode,
-    server::location::Location,
+    server::{location::Location, routing::Routes, server::Server},
 };
 
 #[allow(dead_code)]
@@ -15,9 +23,11 @@ pub trait Config {
     fn auto_index(&self) -> bool;
     fn root(&self) -> Option<&PathBuf>;
     fn upload_folder(&self) -> Option<&PathBuf>;
+    fn file_upload(&self) -> Option<bool>;
     fn alias(&self) -> Option<&PathBuf>;
     fn port(&self) -> Option<&u16>;
     fn index(&self) -> Option<&String>;
+    fn charset(&self) -> Option<&String>;
     fn max_body_size(&self) -> Option<&usize>;
     fn name(&self) -> Option<&Vec<String>>;
     fn path(&self) -> &PathBuf;
@@ -25,10 +35,35 @@ pub trait Config {
     fn cgi(&self) -> &HashMap<String, PathBuf>;
     fn error_pages(&self) -> &HashMap<u16, String>;
     fn error_redirect(&self) -> &HashMap<u16, (Option<u16>, String)>;
-    fn locations(&self) -> Option<&HashMap<PathBuf, Location>>;
+    fn locations(&self) -> Option<&Vec<Location>>;
     fn return_(&self) -> Option<&(u16, Option<String>)>;
     fn internal(&self) -> bool;
     fn is_location(&self) -> bool;
+    fn client_body_timeout(&self) -> Option<&Duration>;
+    fn send_timeout(&self) -> Option<&Duration>;
+    fn server(&self) -> Option<Arc<Server>>;
+    fn regex(&self) -> Option<&Regex>;
+    fn routes(&self) -> Option<&Routes>;
+    fn alias_base(&self) -> Option<&PathBuf>;
+
+    /// `mime` with the configured charset, for the text responses
+    fn content_type(&self, mime: &str) -> String {
+        match self.charset() {
+            Some(charset) => format!("{mime}; charset={charset}"),
+            None => mime.to_owned(),
+        }
+    }
+
+    /// Content-Type of the file at `path` after its extension, with the charset for text
+    fn file_content_type(&self, path: &Path) -> String {
+        let extension = path.extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+
+        match extension.as_deref().and_then(extension_to_content_type) {
+            Some(mime) if mime.starts_with("text/") => self.content_type(mime),
+            Some(mime) => mime.to_owned(),
+            None => "application/octet-stream".to_owned(),
+        }
+    }
 
     /*------------------------------------------------------------*/
     /*-------------------------[ Body ]---------------------------*/
@@ -38,7 +73,7 @@ pub trait Config {
     /*-----------------------[ Parsing ]--------------------------*/
     /*------------------------------------------------------------*/
 
-    fn parse_request(&self, request: &mut Request) -> Result<(), ResponseCode> {
+    fn parse_request(&self, request: &mut Request<'_>) -> Result<(), ResponseCode> {
 
         self.parse_method(request)?;
 
@@ -51,13 +86,13 @@ pub trait Config {
         Ok(())
     }
 
-    fn format_path(&self, request: &mut Request) -> Result<(), ResponseCode> {
+    fn format_path(&self, request: &mut Request<'_>) -> Result<(), ResponseCode> {
         self.add_root_or_alias(request)?;
         self.add_index_if_needed(request)?;
         Ok(())
     }
 
-    fn add_index_if_needed(&self, request: &mut Request) -> Result<(), ResponseCode> {
+    fn add_index_if_needed(&self, request: &mut Request<'_>) -> Result<(), ResponseCode> {
         let path = request.path();
         let path_str = request.path().to_string_lossy();
 
@@ -92,25 +127,30 @@ pub trait Config {
         Ok(None)
     }
 
-    fn add_root_or_alias(&self, request: &mut Request) -> Result<(), ResponseCode> {
+    fn add_root_or_alias(&self, request: &mut Request<'_>) -> Result<(), ResponseCode> {
+        let request_path = request.path().to_string_lossy().to_string();
+        // regex locations: $1..$9 in alias and root refer to the groups of the match
+        let captures = self.regex().and_then(|regex| regex.captures(&request_path));
+        let expand = |path: &PathBuf| match &captures {
+            Some(captures) => utils::expand_captures(&path.to_string_lossy(), captures),
+            None => path.to_string_lossy().to_string(),
+        };
+
         let path = if self.alias().is_some() {
-            let path = request.path().to_string_lossy();
-            let path = path.replacen(
-                &self.path().to_string_lossy().to_string(),
-                &self.alias().unwrap().to_string_lossy().to_string(),
-                1,
-            );
-
-            PathBuf::from(path)
+            let alias = expand(self.alias().unwrap());
+            match self.alias_base() {
+                // inherited: the alias stands for the enclosing location's path
+                Some(base) => PathBuf::from(request_path.replacen(&*base.to_string_lossy(), &alias, 1)),
+                // the alias of a regex location is the whole file path
+                None if captures.is_some() => PathBuf::from(alias),
+                None => PathBuf::from(request_path.replacen(
+                    &self.path().to_string_lossy().to_string(),
+                    &alias,
+                    1,
+                )),
+            }
         } else if self.root().is_some() {
-            let mut path = self.root().unwrap().clone();
-            path = PathBuf::from(format!(
-                "{}{}",
-                path.to_string_lossy(),
-                request.path().to_string_lossy(),
-            ));
-
-            path
+            PathBuf::from(format!("{}{}", expand(self.root().unwrap()), request_path))
         } else {
             return Err(ResponseCode::from_code(404));
         }; // no root nor alias
@@ -125,7 +165,7 @@ pub trait Config {
         Ok(())
     }
 
-    fn parse_method(&self, request: &Request) -> Result<(), ResponseCode> {
+    fn parse_method(&self, request: &Request<'_>) -> Result<(), ResponseCode> {
         let methods = self.methods();
         if methods.is_none() {
             return Err(ResponseCode::from_code(405));
@@ -141,47 +181,13 @@ pub trait Config {
         };
     }
 
-    fn get_request_location<'a>(&'a self, request: &Request) -> Option<&'a Location> {
-        if self.locations().is_none() {
-            return None;
-        }
-
-        let locations = self.locations().unwrap();
-        let mut save: Option<&Location> = None;
-        let mut save_path = None;
-
-        for (location_path, location) in locations {
-            let location_path = match location_path.to_str() {
-                Some(location_path) => location_path,
-                None => continue,
-            };
-
-            let request_path = match request.path().to_str() {
-                Some(path) => path,
-                None => continue,
-            };
-
-            if request_path.starts_with(location_path) {
-                match location.exact_path() {
-                    true => {
-                        if save.is_none() && location_path == request_path {
-                            save = Some(location);
-                            save_path = Some(location_path)
-                        }
-                    }
-                    false => {
-                        if save.is_none() {
-                            save = Some(location);
-                            save_path = Some(location_path)
-                        } else if location_path > save_path.unwrap() {
-                            save = Some(location)
-                        }
-                    }
-                }
-            }
-        }
+    /// Resolved through the routing table compiled with the server, see `Routes::find`.
+    /// The search descends into nested locations: the innermost match handles the request.
+    fn get_request_location<'a>(&'a self, request: &Request<'_>) -> Option<&'a Location> {
+        let index = self.routes()?.find(request.path().to_str()?)?;
+        let location = self.locations()?.get(index)?;
 
-        save
+        location.get_request_location(request).or(Some(location))
     }
 }
 
@@ -195,15 +201,37 @@ pub mod utils {
     use tokio::{
         fs,
         io::{AsyncReadExt, AsyncWriteExt},
-        net::TcpStream,
         process::Child,
     };
 
+    use regex::Captures;
+
     use crate::{
+        listener::stream::ClientStream,
         request::{Method, Request},
         response::response::{Response, ResponseCode},
     };
 
+    /// Replaces `$1`..`$9` (`$0`: whole match) with the groups captured by a regex location
+    pub fn expand_captures(value: &str, captures: &Captures) -> String {
+        let mut expanded = String::with_capacity(value.len());
+        let mut chars = value.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match (c, chars.peek().and_then(|next| next.to_digit(10))) {
+                ('$', Some(group)) => {
+                    chars.next();
+                    if let Some(capture) = captures.get(group as usize) {
+                        expanded.push_str(capture.as_str());
+                    }
+                }
+                _ => expanded.push(c),
+            }
+        }
+
+        expanded
+    }
+
     pub async fn build_auto_index(dir: &PathBuf) -> io::Result<Response> {
         let files_ref = html_files_ref_from(dir).await?;
         let files_ref = format_file_ref(files_ref);
@@ -348,8 +376,8 @@ pub mod utils {
     }
 
     pub async fn send_body_to_cgi(
-        request: &Request,
-        stream: &mut TcpStream,
+        request: &Request<'_>,
+        stream: &mut ClientStream,
         child: &mut Child,
         raw_left: &mut [u8],
         buffer: &mut [u8; 8196],
@@ -400,7 +428,7 @@ pub mod utils {
         }
     }
 
-    pub fn extract_boundary(content_type: Option<&String>) -> Option<String> {
+    pub fn extract_boundary(content_type: Option<&str>) -> Option<String> {
         let to_find = "boundary=";
 
         if content_type.is_none() {
@@ -424,8 +452,8 @@ pub mod utils {
     }
 
     pub async fn consume_body(
-        request: &Request,
-        stream: &mut TcpStream,
+        request: &Request<'_>,
+        stream: &mut ClientStream,
         raw_left: &mut [u8],
         buffer: &mut [u8; 8196],
     ) -> Result<Vec<u8>, io::Error> {
@@ -441,7 +469,7 @@ pub mod utils {
     }
 
     async fn consume_stream(
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         len: usize,
         buffer: &mut [u8; 8196],
     ) -> io::Result<Vec<u8>> {
@@ -467,7 +495,7 @@ pub mod utils {
         Multipart,
     }
 
-    pub fn choose_upload_type(request: &Request) -> UploadType {
+    pub fn choose_upload_type(request: &Request<'_>) -> UploadType {
         let content_type = request.content_type();
 
         if content_type.is_none() {
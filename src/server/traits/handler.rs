@@ -9,14 +9,15 @@ use nom::{AsBytes, FindSubstring};
 use tokio::{
     fs::{File, OpenOptions},
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
     process::Command,
 };
 
 use crate::{
-    listener::send_error_response,
+    listener::{send_error_response, stream::ClientStream},
+    server::{parsing, server::Server},
     request::{Method, Request},
     response::response::{Response, ResponseCode},
+    server::{DEFAULT_CLIENT_BODY_TIMEOUT, DEFAULT_SEND_TIMEOUT},
 };
 
 use super::config::{
@@ -33,17 +34,38 @@ pub struct MultipartFile {
 pub trait Handler: Config {
     async fn handle_request(
         &self,
-        mut request: Request,
-        stream: &mut TcpStream,
+        mut request: Request<'_>,
+        stream: &mut ClientStream,
         raw_left: &mut [u8],
         buffer: &mut [u8; 8196],
     ) -> Option<Vec<u8>> {
+        stream.set_read_timeout(Some(
+            self.client_body_timeout().copied().unwrap_or(DEFAULT_CLIENT_BODY_TIMEOUT),
+        ));
+        stream.set_write_timeout(Some(
+            self.send_timeout().copied().unwrap_or(DEFAULT_SEND_TIMEOUT),
+        ));
+
+        // internal: every request handled here comes from a client, error pages
+        // reach internal locations through `build_error_page` only
+        if self.internal() {
+            eprintln!("Error: {}: internal location requested by a client", request.request_uri());
+            return self.handle_return(404, None, &request, stream, raw_left, buffer).await;
+        }
+
+        // return / redirect: answered before any file resolution
+        if let Some((code, value)) = self.return_() {
+            return self
+                .handle_return(*code, value.as_deref(), &request, stream, raw_left, buffer)
+                .await;
+        }
+
         match self.parse_request(&mut request) {
             Ok(location) => location,
             Err(err) => {
                 eprintln!("Error: parsing request: {}", err.to_string());
-                send_error_response(stream, err, buffer).await;
-                return if request.keep_connection_alive() == true {
+                self.send_error(stream, err, buffer).await;
+                return if request.keep_connection_alive() {
                     Some(raw_left.to_vec())
                 } else {
                     None
@@ -61,10 +83,70 @@ pub trait Handler: Config {
         return raw_left;
     }
 
+    async fn handle_return(
+        &self,
+        code: u16,
+        value: Option<&str>,
+        request: &Request<'_>,
+        stream: &mut ClientStream,
+        raw_left: &mut [u8],
+        buffer: &mut [u8; 8196],
+    ) -> Option<Vec<u8>> {
+        let raw_left = match request.content_length() {
+            Some(_) => match utils::consume_body(request, stream, raw_left, buffer).await {
+                Ok(raw_left) => raw_left,
+                Err(_) => return None,
+            },
+            None => raw_left.to_vec(),
+        };
+
+        if code == 444 {
+            return None; // close the connection without response
+        }
+
+        let value = value.map(|value| request.expand_variables(value));
+
+        let mut response = if parsing::is_redirect_status_code(code) {
+            let location = Self::absolute_url(request, value.unwrap_or_default());
+            let code = ResponseCode::from_code(code);
+            let code = ResponseCode::new_redirect(code.code(), code.msg(), PathBuf::from(location));
+            Response::new(code, *request.method())
+        } else if let Some(text) = value {
+            let mut response = Response::new(ResponseCode::from_code(code), *request.method());
+            response.set_content(text);
+            response.add_header("Content-Type".to_owned(), self.content_type("text/plain"));
+            response
+        } else if code >= 400 {
+            self.send_error(stream, ResponseCode::from_code(code), buffer).await;
+            return Some(raw_left).filter(|_| request.keep_connection_alive());
+        } else {
+            Response::new(ResponseCode::from_code(code), *request.method())
+        };
+
+        if let Err(err) = response.send(stream, buffer).await {
+            eprintln!("Error: sending response: {err}");
+            return None;
+        }
+
+        Some(raw_left).filter(|_| request.keep_connection_alive())
+    }
+
+    /// Relative redirect targets are made absolute with the request's Host
+    fn absolute_url(request: &Request<'_>, url: String) -> String {
+        if parsing::is_url(&url) || !url.starts_with('/') {
+            return url;
+        }
+
+        match request.host() {
+            Some(host) => format!("http://{host}{url}"),
+            None => url,
+        }
+    }
+
     async fn handle_non_cgi(
         &self,
-        request: &Request,
-        stream: &mut TcpStream,
+        request: &Request<'_>,
+        stream: &mut ClientStream,
         raw_left: &mut [u8],
         buffer: &mut [u8; 8196],
     ) -> Option<Vec<u8>> {
@@ -93,7 +175,7 @@ pub trait Handler: Config {
 
                     _ => {
                         println!("Error: handling body: {}", err.to_string());
-                        send_error_response(stream, ResponseCode::from_error(&err), buffer).await;
+                        self.send_error(stream, ResponseCode::from_error(&err), buffer).await;
 						return None; // kill stream
                     }
                 }
@@ -104,12 +186,13 @@ pub trait Handler: Config {
             Ok(_) => (),
             Err(err) => {
                 println!("Error: sending response: {err}");
-                send_error_response(stream, ResponseCode::from_error(&err), buffer).await;
-                if err.kind() == ErrorKind::UnexpectedEof { return None }
+                // send_timeout: the client does not read, nothing more can be sent
+                if err.kind() == ErrorKind::UnexpectedEof || err.kind() == ErrorKind::TimedOut { return None }
+                self.send_error(stream, ResponseCode::from_error(&err), buffer).await;
             }
         }
 
-        if request.keep_connection_alive() == true
+        if request.keep_connection_alive()
 		{
 			return Some(raw_left)
 		} else {
@@ -119,8 +202,8 @@ pub trait Handler: Config {
 
     async fn handle_cgi(
         &self,
-        request: &Request,
-        stream: &mut TcpStream,
+        request: &Request<'_>,
+        stream: &mut ClientStream,
         raw_left: &mut [u8],
         buffer: &mut [u8; 8196],
     ) -> Option<Vec<u8>> {
@@ -132,8 +215,10 @@ pub trait Handler: Config {
                     "Error : {}: sending response: {err}",
                     request.path().display()
                 );
-                send_error_response(stream, ResponseCode::from_error(&err), buffer).await;
-                if request.keep_connection_alive() == true && err.kind() != ErrorKind::UnexpectedEof
+                self.send_error(stream, ResponseCode::from_error(&err), buffer).await;
+                if request.keep_connection_alive()
+                    && err.kind() != ErrorKind::UnexpectedEof
+                    && err.kind() != ErrorKind::TimedOut
                 {
                     return Some(raw_left.to_owned());
                 } else {
@@ -146,9 +231,9 @@ pub trait Handler: Config {
             Ok(_) => (),
             Err(err) => {
                 println!("Error: sending response: {err}");
-                send_error_response(stream, ResponseCode::from_error(&err), buffer).await;
-                if request.keep_connection_alive() == true && err.kind() != ErrorKind::UnexpectedEof
-                {
+                if err.kind() == ErrorKind::UnexpectedEof || err.kind() == ErrorKind::TimedOut { return None }
+                self.send_error(stream, ResponseCode::from_error(&err), buffer).await;
+                if request.keep_connection_alive() {
                     return Some(raw_left.to_owned());
                 } else {
                     return None;
@@ -160,16 +245,19 @@ pub trait Handler: Config {
         Some(raw_left)
     }
 
-    async fn send_cgi_response(cgi_output: Output, stream: &mut TcpStream) -> io::Result<()> {
+    async fn send_cgi_response(cgi_output: Output, stream: &mut ClientStream) -> io::Result<()> {
         stream.write_all("HTTP/1.1 200 OK\r\n".as_bytes()).await?;
+        if stream.closing() {
+            stream.write_all("Connection: close\r\n".as_bytes()).await?;
+        }
 
         stream.write_all(&cgi_output.stdout).await
     }
 
     async fn handle_request_body(
         &self,
-        request: &Request,
-        stream: &mut TcpStream,
+        request: &Request<'_>,
+        stream: &mut ClientStream,
         raw_left: &mut [u8],
         buffer: &mut [u8; 8196],
     ) -> Result<Vec<u8>, io::Error> {
@@ -189,7 +277,7 @@ pub trait Handler: Config {
     /*------------------------------------------------------------*/
     async fn handle_request_method(
         &self,
-        request: &Request,
+        request: &Request<'_>,
     ) -> Result<(), io::Error> {
         match request.method() {
 			&Method::DELETE => self.handle_delete(request).await,
@@ -197,7 +285,7 @@ pub trait Handler: Config {
         }
     }
 
-	async fn handle_delete(&self, request: &Request) -> Result<(), io::Error> {
+	async fn handle_delete(&self, request: &Request<'_>) -> Result<(), io::Error> {
 		if request.path().exists() == false {
 			return Err(io::Error::new(ErrorKind::NotFound, "file not found"))
 		} else if request.path().is_dir() {
@@ -213,14 +301,17 @@ pub trait Handler: Config {
 
     async fn handle_post(
         &self,
-        request: &Request,
-        stream: &mut TcpStream,
+        request: &Request<'_>,
+        stream: &mut ClientStream,
         raw_left: &[u8],
         buffer: &mut [u8; 8196],
     ) -> Result<Vec<u8>, io::Error> {
 
         if request.content_length().is_none() {
             return Ok(raw_left.to_vec());
+        } else if self.file_upload() == Some(false) {
+            eprintln!("file_upload off");
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "Uploads are disabled"));
         } else if self.upload_folder().is_none() {
             eprintln!("no upload folder");
             return Err(io::Error::new(ErrorKind::NotFound, "No upload folder"));
@@ -256,8 +347,8 @@ pub trait Handler: Config {
 
     async fn handle_mutlipart_upload(
         &self,
-        request: &Request,
-        stream: &mut TcpStream,
+        request: &Request<'_>,
+        stream: &mut ClientStream,
         raw_left: &[u8],
         upload_folder: &PathBuf,
         buffer: &mut [u8; 8196],
@@ -284,7 +375,7 @@ pub trait Handler: Config {
     }
 
     async fn upload_multipart_content(
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         content_len: usize,
         raw_left: &[u8],
         boundary: String,
@@ -362,7 +453,7 @@ pub trait Handler: Config {
     async fn create_and_upload(
         file: MultipartFile,
         raw_left: Vec<u8>,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         upload_folder: &PathBuf,
         boundary: &[u8],
         buffer: &mut [u8; 8196],
@@ -418,7 +509,7 @@ pub trait Handler: Config {
 
     async fn deserialize_multipart_header(
         read_limit: usize,
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         raw_left: &[u8],
         buffer: &mut [u8; 8196],
     ) -> io::Result<(MultipartFile, Vec<u8>, usize)> {
@@ -493,7 +584,7 @@ pub trait Handler: Config {
         to_find: &[u8],
         read_limit: usize,
         raw_left: &[u8],
-        stream: &mut TcpStream,
+        stream: &mut ClientStream,
         buffer: &mut [u8; 8196],
     ) -> io::Result<(Vec<u8>, Option<usize>)> {
         if let Some(index) = utils::find_in(raw_left, to_find) {
@@ -535,8 +626,8 @@ pub trait Handler: Config {
 
     async fn upload_default_content(
         &self,
-        request: &Request,
-        stream: &mut TcpStream,
+        request: &Request<'_>,
+        stream: &mut ClientStream,
         raw_left: &[u8],
         upload_folder: &PathBuf,
         buffer: &mut [u8; 8196],
@@ -562,8 +653,8 @@ pub trait Handler: Config {
     async fn default_upload(
         &self,
         file: &mut File,
-        stream: &mut TcpStream,
-        request: &Request,
+        stream: &mut ClientStream,
+        request: &Request<'_>,
         raw_left: &[u8],
         buffer: &mut [u8; 8196],
     ) -> Result<Vec<u8>, io::Error> {
@@ -601,7 +692,7 @@ pub trait Handler: Config {
     /*-----------------------[ Response ]-------------------------*/
     /*------------------------------------------------------------*/
 
-    async fn build_response(&self, request: &Request) -> Result<Response, io::Error> {
+    async fn build_response(&self, request: &Request<'_>) -> Result<Response, io::Error> {
         if request.path().is_dir() {
             return utils::build_auto_index(request.path()).await;
         }
@@ -615,8 +706,8 @@ pub trait Handler: Config {
 
     async fn send_response(
         &self,
-        stream: &mut TcpStream,
-        request: &Request,
+        stream: &mut ClientStream,
+        request: &Request<'_>,
         buffer: &mut [u8; 8196],
     ) -> Result<(), io::Error> {
 		let mut response = self.build_response(request).await?;
@@ -628,18 +719,18 @@ pub trait Handler: Config {
     /*-------------------------[ GET ]----------------------------*/
     /*------------------------------------------------------------*/
 
-    async fn build_get_response(&self, request: &Request) -> Result<Response, io::Error> {
+    async fn build_get_response(&self, request: &Request<'_>) -> Result<Response, io::Error> {
         let file = self.get_GET_request_file(request).await?;
 
         let mut response = Response::new(ResponseCode::default(), Method::GET).file(file);
 
-        response.add_header("Content-Type".to_owned(), "text/html".to_owned());
+        response.add_header("Content-Type".to_owned(), self.file_content_type(request.path()));
 
         Ok(response)
     }
 
     #[allow(non_snake_case)]
-    async fn get_GET_request_file(&self, request: &Request) -> io::Result<File> {
+    async fn get_GET_request_file(&self, request: &Request<'_>) -> io::Result<File> {
         if request.path().is_file() {
             match File::open(request.path()).await {
                 Ok(file) => Ok(file),
@@ -650,11 +741,92 @@ pub trait Handler: Config {
         }
     }
 
+    /*------------------------------------------------------------*/
+    /*------------------------[ Errors ]--------------------------*/
+    /*------------------------------------------------------------*/
+
+    async fn send_error(
+        &self,
+        stream: &mut ClientStream,
+        code: ResponseCode,
+        buffer: &mut [u8; 8196],
+    ) {
+        match self.build_error_response(&code).await {
+            Some(mut response) => {
+                let _ = response.send(stream, buffer).await;
+            }
+            None => send_error_response(stream, code, buffer).await,
+        }
+    }
+
+    /// Applies the `error_page` directives matching `code`, if any
+    async fn build_error_response(&self, code: &ResponseCode) -> Option<Response> {
+        if let Some((new_code, url)) = self.error_redirect().get(&code.code()) {
+            let redirect_code = new_code.filter(|code| parsing::is_redirect_status_code(*code));
+
+            if parsing::is_url(url) || redirect_code.is_some() {
+                let code = ResponseCode::from_code(redirect_code.unwrap_or(302));
+                let code = ResponseCode::new_redirect(code.code(), code.msg(), PathBuf::from(url));
+                return Some(Response::new(code, Method::UNDEFINED));
+            }
+
+            // '=' without code: like nginx, the status of the page served. Error
+            // pages are static files, so 200; a missing page falls back to the
+            // original error, where nginx would answer with the page's own 404
+            let code = ResponseCode::from_code(new_code.unwrap_or(200));
+            return self.build_error_page(code, url).await;
+        }
+
+        match self.error_pages().get(&code.code()) {
+            Some(page) => self.build_error_page(code.clone(), page).await,
+            None => None,
+        }
+    }
+
+    /// Serves `page` through an internal subrequest on the server's locations.
+    /// Errors met while doing so are never intercepted again, the caller falls
+    /// back to the bare error response instead.
+    async fn build_error_page(&self, code: ResponseCode, page: &str) -> Option<Response> {
+        let mut subrequest = Request::subrequest(page);
+
+        let resolved = match self.server() {
+            Some(server) => Self::resolve_subrequest(&server, &mut subrequest),
+            None => match self.get_request_location(&subrequest) {
+                Some(location) => location.format_path(&mut subrequest),
+                None => self.format_path(&mut subrequest),
+            },
+        };
+
+        let file = match resolved {
+            Ok(()) if subrequest.path().is_file() => File::open(subrequest.path()).await,
+            _ => Err(io::Error::new(ErrorKind::NotFound, "file not found")),
+        };
+
+        match file {
+            Ok(file) => {
+                let mut response = Response::new(code, Method::GET).file(file);
+                response.add_header("Content-Type".to_owned(), self.file_content_type(subrequest.path()));
+                Some(response)
+            }
+            Err(err) => {
+                eprintln!("Error: error_page {page}: {err}");
+                None
+            }
+        }
+    }
+
+    fn resolve_subrequest(server: &Server, subrequest: &mut Request<'_>) -> Result<(), ResponseCode> {
+        match server.get_request_location(subrequest) {
+            Some(location) => location.format_path(subrequest),
+            None => server.format_path(subrequest),
+        }
+    }
+
     /*------------------------------------------------------------*/
     /*-------------------------[ CGI ]----------------------------*/
     /*------------------------------------------------------------*/
 
-    fn is_cgi(&self, request: &Request) -> bool {
+    fn is_cgi(&self, request: &Request<'_>) -> bool {
         let path = request.path();
 
         if path.extension().is_none() {
@@ -671,8 +843,8 @@ pub trait Handler: Config {
 
     async fn execute_cgi(
         &self,
-        request: &Request,
-        stream: &mut TcpStream,
+        request: &Request<'_>,
+        stream: &mut ClientStream,
         raw_left: &mut [u8],
         buffer: &mut [u8; 8196],
     ) -> Result<(Output, Vec<u8>), io::Error> {
@@ -713,13 +885,14 @@ pub trait Handler: Config {
         Ok((output, raw_left))
     }
 
-    fn cgi_envs(&self, request: &Request) -> HashMap<String, String> {
-        let mut env: HashMap<String, String> = HashMap::new();
+    fn cgi_envs(&self, request: &Request<'_>) -> HashMap<String, String> {
+        // named captures of the regex server_name first, the CGI variables override them
+        let mut env: HashMap<String, String> = request.server_name_captures().iter().cloned().collect();
 
         env.insert("REQUEST_METHOD".to_owned(), request.method().to_string());
         env.insert(
             "HTTP_CONNECTION".to_owned(),
-            if request.keep_connection_alive() == true {
+            if request.keep_connection_alive() {
                 "keep-alive"
             } else {
                 "close "
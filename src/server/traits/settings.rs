@@ -0,0 +1,67 @@
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, time::Duration};
+
+use crate::{request::Method, server::server_names::Strict};
+
+/// Result of a setter, the error is reported as a configuration error
+pub type Applied = Result<(), String>;
+
+fn not_allowed(name: &str) -> Applied {
+    Err(format!("\"{name}\" directive is not allowed here"))
+}
+
+/// Setters called by the directive registry (`server::directives`), the
+/// counterpart of `Config`. A setter is only called in the contexts its
+/// directive is declared for, the other types keep the default, which fails.
+#[allow(unused_variables)]
+pub trait Settings {
+    fn is_regex_location(&self) -> bool { false }
+
+    /*------------------------------------------------------------*/
+    /*------------------[ Server and Location ]-------------------*/
+    /*------------------------------------------------------------*/
+
+    fn set_root(&mut self, root: PathBuf) -> Applied { not_allowed("root") }
+    fn set_upload_folder(&mut self, folder: PathBuf) -> Applied { not_allowed("upload_folder") }
+    fn set_file_upload(&mut self, on: bool) -> Applied { not_allowed("file_upload") }
+    fn set_index(&mut self, index: String) -> Applied { not_allowed("index") }
+    fn set_auto_index(&mut self, on: bool) -> Applied { not_allowed("auto_index") }
+    fn set_max_body_size(&mut self, size: usize) -> Applied { not_allowed("client_max_body_size") }
+    fn set_charset(&mut self, charset: String) -> Applied { not_allowed("charset") }
+    fn add_cgi(&mut self, extension: String, path: PathBuf) -> Applied { not_allowed("cgi") }
+    fn add_methods(&mut self, methods: Vec<Method>) -> Applied { not_allowed("allowed_methods") }
+    fn set_return(&mut self, return_: (u16, Option<String>)) -> Applied { not_allowed("return") }
+    fn add_error_pages(&mut self, pages: HashMap<u16, String>) -> Applied { not_allowed("error_page") }
+    fn add_error_redirects(&mut self, redirects: HashMap<u16, (Option<u16>, String)>) -> Applied { not_allowed("error_page") }
+    fn set_internal(&mut self) -> Applied { not_allowed("internal") }
+    fn set_client_body_timeout(&mut self, timeout: Duration) -> Applied { not_allowed("client_body_timeout") }
+    fn set_send_timeout(&mut self, timeout: Duration) -> Applied { not_allowed("send_timeout") }
+
+    /*------------------------------------------------------------*/
+    /*----------------------[ Location only ]---------------------*/
+    /*------------------------------------------------------------*/
+
+    fn set_alias(&mut self, alias: PathBuf) -> Applied { not_allowed("alias") }
+    fn set_redirect(&mut self, url: String) -> Applied { not_allowed("redirect") }
+
+    /*------------------------------------------------------------*/
+    /*-----------------------[ Server only ]----------------------*/
+    /*------------------------------------------------------------*/
+
+    fn set_listen(&mut self, address: Option<IpAddr>, port: u16, default: bool) -> Applied { not_allowed("listen") }
+    fn add_server_names(&mut self, names: Vec<String>) -> Applied { not_allowed("server_name") }
+    fn set_strict_server_name(&mut self, strict: Strict) -> Applied { not_allowed("strict_server_name") }
+    fn set_limit_conn(&mut self, key: String, max: usize) -> Applied { not_allowed("limit_conn") }
+    fn set_client_header_timeout(&mut self, timeout: Duration) -> Applied { not_allowed("client_header_timeout") }
+    fn set_keepalive_timeout(&mut self, timeout: Duration) -> Applied { not_allowed("keepalive_timeout") }
+    fn set_keepalive_requests(&mut self, max: usize) -> Applied { not_allowed("keepalive_requests") }
+    fn set_client_header_buffer_size(&mut self, size: usize) -> Applied { not_allowed("client_header_buffer_size") }
+    fn set_large_client_header_buffers(&mut self, buffers: (usize, usize)) -> Applied { not_allowed("large_client_header_buffers") }
+    fn set_client_max_headers(&mut self, max: usize) -> Applied { not_allowed("client_max_headers") }
+
+    /*------------------------------------------------------------*/
+    /*-----------------------[ Main context ]---------------------*/
+    /*------------------------------------------------------------*/
+
+    fn set_worker_connections(&mut self, max: usize) -> Applied { not_allowed("worker_connections") }
+    fn set_pid(&mut self, path: PathBuf) -> Applied { not_allowed("pid") }
+}